@@ -0,0 +1,188 @@
+use crate::error::{self, context::Status, ErrReport};
+use crate::storage::Storage;
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::time::SystemTime;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// The most codes that can be issued by a single request.
+const MAX_CODES_PER_REQUEST: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+struct IssueRequest {
+    count: usize,
+    /// How long the codes are accepted for, in seconds.  Codes without
+    /// expiry are accepted until they are used or revoked.
+    expires_in_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IssuedCode {
+    id: String,
+    code: String,
+    expires_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OutstandingCode {
+    id: String,
+    expires_at: Option<u64>,
+}
+
+/// The admin API for managing authorization codes.
+///
+/// * `POST /codes` with `{"count": n, "expires_in_secs": s}` issues `n` new
+///   codes, returning each code along with its id.  The codes themselves are
+///   never stored, so this is the only time they are revealed.
+/// * `GET /codes` lists the ids and expiry times of codes that are neither
+///   used nor expired.
+/// * `DELETE /codes/<id>` revokes a code.
+///
+/// These routes have no authentication of their own, so they must only be
+/// served on an address that is not reachable by clients.
+pub(crate) fn routes(
+    storage: &'static Storage,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let issue = warp::path!("codes")
+        .and(warp::filters::method::post())
+        .and(warp::filters::body::content_length_limit(1024))
+        .and(warp::filters::body::bytes())
+        .and_then(move |body: bytes::Bytes| async move {
+            issue_codes(storage, &body)
+                .map(|codes| warp::reply::json(&codes))
+                .map_err(error::into_warp)
+        });
+
+    let list = warp::path!("codes")
+        .and(warp::filters::method::get())
+        .map(move || {
+            let codes: Vec<_> = storage
+                .outstanding_codes()
+                .into_iter()
+                .map(|(id, expires_at)| OutstandingCode {
+                    id: hex::encode(id),
+                    expires_at,
+                })
+                .collect();
+            warp::reply::json(&codes)
+        });
+
+    let revoke = warp::path!("codes" / String)
+        .and(warp::filters::method::delete())
+        .and_then(move |id: String| async move {
+            revoke_code(storage, &id)
+                .map(|_| StatusCode::NO_CONTENT)
+                .map_err(error::into_warp)
+        });
+
+    issue.or(list).or(revoke)
+}
+
+fn issue_codes(storage: &Storage, body: &[u8]) -> Result<Vec<IssuedCode>, ErrReport> {
+    let request: IssueRequest = serde_json::from_slice(body)
+        .map_err(|e| ErrReport::from(e).wrap_err("Invalid request body"))
+        .set_status(StatusCode::BAD_REQUEST)?;
+    if request.count == 0 || request.count > MAX_CODES_PER_REQUEST {
+        return Err(eyre!(
+            "Can issue between 1 and {} codes at a time, not {}",
+            MAX_CODES_PER_REQUEST,
+            request.count
+        ))
+        .set_status(StatusCode::BAD_REQUEST);
+    }
+
+    let expires_at = match request.expires_in_secs {
+        Some(secs) => {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+            Some(now.as_secs().saturating_add(secs))
+        }
+        None => None,
+    };
+    Ok(storage
+        .issue_codes(request.count, expires_at)?
+        .into_iter()
+        .map(|(code, id)| IssuedCode {
+            id: hex::encode(id),
+            code,
+            expires_at,
+        })
+        .collect())
+}
+
+fn revoke_code(storage: &Storage, id: &str) -> Result<(), ErrReport> {
+    let id = hex::decode(id)
+        .ok()
+        .and_then(|id| id.as_slice().try_into().ok())
+        .ok_or(eyre!("Code ids are 64 hexadecimal digits"))
+        .set_status(StatusCode::BAD_REQUEST)?;
+    storage.revoke_code(id)
+}
+
+#[tokio::test]
+async fn test_admin_api() {
+    use crate::storage::DiskStore;
+    use warp::test::request;
+
+    let dir = tempfile::tempdir().unwrap();
+    let open = || -> &'static Storage {
+        let store = DiskStore::open(dir.path()).unwrap();
+        Box::leak(Box::new(Storage::new(store).unwrap().require_auth_code()))
+    };
+    let storage = open();
+    let api = routes(storage).recover(error::handle_rejection);
+
+    let response = request()
+        .method("POST")
+        .path("/codes")
+        .body(r#"{"count": 3, "expires_in_secs": 3600}"#)
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let issued: Vec<IssuedCode> = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(issued.len(), 3);
+    assert!(issued.iter().all(|c| c.expires_at.is_some()));
+
+    let response = request()
+        .method("POST")
+        .path("/codes")
+        .body(r#"{"count": 0}"#)
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = request()
+        .method("DELETE")
+        .path(&format!("/codes/{}", issued[0].id))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = request()
+        .method("DELETE")
+        .path(&format!("/codes/{}", issued[0].id))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Submitting a report consumes a code.
+    let report = tcn::ReportAuthorizationKey::new(rand::rngs::OsRng)
+        .create_report(tcn::MemoType::CoEpiV1, Vec::new(), 1, 10)
+        .unwrap();
+    let authorization = format!("Bearer {}", issued[1].code);
+    storage
+        .save(crate::Shard(1), report, Some(&authorization))
+        .await
+        .unwrap();
+
+    // Codes survive a restart.
+    let storage = open();
+    let api = routes(storage).recover(error::handle_rejection);
+    let response = request().method("GET").path("/codes").reply(&api).await;
+    let outstanding: Vec<_> = serde_json::from_slice::<Vec<OutstandingCode>>(response.body())
+        .unwrap()
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    assert_eq!(outstanding, vec![issued[2].id.clone()]);
+}
@@ -0,0 +1,348 @@
+use crate::error::{context::Status, ErrReport};
+use eyre::eyre;
+use rand::{rngs::OsRng, Rng};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use warp::http::StatusCode;
+
+/// Identifies a code by the SHA-256 hash of the code itself, so that neither
+/// the registry nor the storage backend holds a usable code.
+pub(crate) type CodeId = [u8; 32];
+
+/// The persisted state of an authorization code.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CodeRecord {
+    /// When the code stops being accepted, in seconds since the Unix epoch.
+    pub(crate) expires_at: Option<u64>,
+    /// The signatures of the reports submitted with the code, if it has been
+    /// consumed.
+    pub(crate) consumed_by: Option<Vec<[u8; 64]>>,
+    /// Whether the reports in `consumed_by` were still being stored, so that
+    /// the code is only consumed if they are found on restart.
+    pub(crate) pending: bool,
+    /// Whether the code was revoked.  Revoked codes are kept, so that they
+    /// cannot be issued or imported again.
+    pub(crate) revoked: bool,
+}
+
+impl CodeRecord {
+    /// Whether this record replaces `earlier` as the state of the same code.
+    ///
+    /// Revocation is final, so nothing replaces a revoked record, even a
+    /// record written by a submission that was storing reports at the time.
+    pub(crate) fn replaces(&self, earlier: &CodeRecord) -> bool {
+        !earlier.revoked
+    }
+}
+
+/// One-time authorization codes issued by a health authority.
+///
+/// A submission must carry an unused code in an `Authorization: Bearer <code>`
+/// header, and the code is consumed when the submission is stored.
+#[derive(Default)]
+pub(crate) struct AuthCodes {
+    codes: Mutex<HashMap<CodeId, Code>>,
+}
+
+struct Code {
+    expires_at: Option<u64>,
+    state: CodeState,
+}
+
+enum CodeState {
+    Issued,
+    /// A submission using the code is being stored.
+    Pending,
+    /// The code was used to submit the reports with these signatures.
+    Consumed(Vec<[u8; 64]>),
+    Revoked,
+}
+
+pub(crate) fn code_id(code: &str) -> CodeId {
+    let mut id = [0; 32];
+    id.copy_from_slice(&Sha256::digest(code.as_bytes()));
+    id
+}
+
+/// Generate a random code of 16 characters from an alphabet without easily
+/// confused characters, for about 80 bits of entropy.
+pub(crate) fn generate_code() -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let mut rng = OsRng;
+    (0..16)
+        .map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())] as char)
+        .collect()
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl AuthCodes {
+    /// Rebuild the registry from persisted codes, which must not be pending.
+    pub(crate) fn from_records(records: impl IntoIterator<Item = (CodeId, CodeRecord)>) -> Self {
+        let codes = records
+            .into_iter()
+            .map(|(id, record)| {
+                let state = match record.consumed_by {
+                    _ if record.revoked => CodeState::Revoked,
+                    Some(signatures) => CodeState::Consumed(signatures),
+                    None => CodeState::Issued,
+                };
+                let code = Code {
+                    expires_at: record.expires_at,
+                    state,
+                };
+                (id, code)
+            })
+            .collect();
+        Self {
+            codes: Mutex::new(codes),
+        }
+    }
+
+    /// Accept the code with this id for one future submission, returning the
+    /// record to persist, or `None` if the code is already known, including
+    /// if it was revoked.
+    pub(crate) fn issue(&self, id: CodeId, expires_at: Option<u64>) -> Option<CodeRecord> {
+        let mut codes = self.codes.lock().unwrap();
+        if codes.contains_key(&id) {
+            return None;
+        }
+        codes.insert(
+            id,
+            Code {
+                expires_at,
+                state: CodeState::Issued,
+            },
+        );
+        Some(CodeRecord {
+            expires_at,
+            ..CodeRecord::default()
+        })
+    }
+
+    /// Stop accepting a code, returning the record to persist.
+    pub(crate) fn revoke(&self, id: &CodeId) -> Result<CodeRecord, ErrReport> {
+        let mut codes = self.codes.lock().unwrap();
+        let code = codes
+            .get_mut(id)
+            .ok_or(eyre!("Unknown authorization code"))
+            .set_status(StatusCode::NOT_FOUND)?;
+        if let CodeState::Revoked = code.state {
+            return Err(eyre!("Authorization code was already revoked"))
+                .set_status(StatusCode::NOT_FOUND);
+        }
+        code.state = CodeState::Revoked;
+        Ok(CodeRecord {
+            expires_at: code.expires_at,
+            revoked: true,
+            ..CodeRecord::default()
+        })
+    }
+
+    /// List the codes that are neither consumed nor expired, with their
+    /// expiry times.
+    pub(crate) fn outstanding(&self) -> Vec<(CodeId, Option<u64>)> {
+        let now = unix_now();
+        self.codes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, code)| match code.state {
+                CodeState::Issued => code.expires_at.map_or(true, |t| t > now),
+                CodeState::Pending | CodeState::Consumed(_) | CodeState::Revoked => false,
+            })
+            .map(|(id, code)| (*id, code.expires_at))
+            .collect()
+    }
+
+    /// Reserve the code in an `Authorization` header for a submission of the
+    /// reports with the given signatures.
+    ///
+    /// The code is consumed by [`Reservation::commit`] once the reports are
+    /// stored, and released again if the reservation is dropped first.  A
+    /// retry of the submission that consumed a code is allowed through, so
+    /// that it can be answered like any other duplicate.
+    pub(crate) fn reserve(
+        &self,
+        authorization: Option<&str>,
+        signatures: &[[u8; 64]],
+    ) -> Result<Reservation<'_>, ErrReport> {
+        let code = authorization
+            .ok_or(eyre!("Submitting reports requires an authorization code"))
+            .set_status(StatusCode::UNAUTHORIZED)?;
+        let code = code
+            .strip_prefix("Bearer ")
+            .ok_or(eyre!("Authorization header must be `Bearer <code>`"))
+            .set_status(StatusCode::UNAUTHORIZED)?;
+
+        let id = code_id(code.trim());
+        let mut codes = self.codes.lock().unwrap();
+        let code = codes
+            .get_mut(&id)
+            .ok_or(eyre!("Unknown authorization code"))
+            .set_status(StatusCode::FORBIDDEN)?;
+        let fresh = match code.state {
+            CodeState::Consumed(ref consumed)
+                if signatures.iter().all(|sig| consumed.contains(sig)) =>
+            {
+                false
+            }
+            CodeState::Pending | CodeState::Consumed(_) => {
+                return Err(eyre!("Authorization code has already been used"))
+                    .set_status(StatusCode::FORBIDDEN);
+            }
+            CodeState::Revoked => {
+                return Err(eyre!("Authorization code has been revoked"))
+                    .set_status(StatusCode::FORBIDDEN);
+            }
+            CodeState::Issued if code.expires_at.map_or(false, |t| t <= unix_now()) => {
+                return Err(eyre!("Authorization code has expired"))
+                    .set_status(StatusCode::FORBIDDEN);
+            }
+            CodeState::Issued => {
+                code.state = CodeState::Pending;
+                true
+            }
+        };
+        Ok(Reservation {
+            codes: self,
+            id,
+            expires_at: code.expires_at,
+            fresh,
+        })
+    }
+}
+
+/// A code set aside for a submission that is being stored.
+pub(crate) struct Reservation<'a> {
+    codes: &'a AuthCodes,
+    id: CodeId,
+    expires_at: Option<u64>,
+    /// Whether this reservation moved the code out of the issued state.
+    fresh: bool,
+}
+
+impl Reservation<'_> {
+    /// The record to persist before the reports with these signatures are
+    /// stored, if the code's state will change, so that a submission
+    /// interrupted by a crash can be resolved on restart.
+    pub(crate) fn pending(&self, signatures: &[[u8; 64]]) -> Option<(CodeId, CodeRecord)> {
+        if !self.fresh {
+            return None;
+        }
+        let record = CodeRecord {
+            expires_at: self.expires_at,
+            consumed_by: Some(signatures.to_vec()),
+            pending: true,
+            revoked: false,
+        };
+        Some((self.id, record))
+    }
+
+    /// Mark the code as consumed by the reports with these signatures,
+    /// returning the record to persist if the code's state changed.
+    pub(crate) fn commit(mut self, signatures: Vec<[u8; 64]>) -> Option<(CodeId, CodeRecord)> {
+        if !self.fresh {
+            return None;
+        }
+        self.fresh = false;
+
+        let mut codes = self.codes.codes.lock().unwrap();
+        let code = codes.get_mut(&self.id)?;
+        // The code may have been revoked while the submission was stored.
+        if let CodeState::Revoked = code.state {
+            return None;
+        }
+        code.state = CodeState::Consumed(signatures.clone());
+        let record = CodeRecord {
+            expires_at: code.expires_at,
+            consumed_by: Some(signatures),
+            ..CodeRecord::default()
+        };
+        Some((self.id, record))
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.fresh {
+            if let Some(code) = self.codes.codes.lock().unwrap().get_mut(&self.id) {
+                if let CodeState::Pending = code.state {
+                    code.state = CodeState::Issued;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_codes_are_single_use() {
+    let codes = AuthCodes::default();
+    codes.issue(code_id("abc123"), None).unwrap();
+    codes.issue(code_id("expired"), Some(1)).unwrap();
+    assert!(codes.issue(code_id("abc123"), None).is_none());
+    let status = |result: Result<_, ErrReport>| result.err().map(|e| e.0.context().status);
+
+    assert_eq!(
+        status(codes.reserve(None, &[[1; 64]])),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+    assert_eq!(
+        status(codes.reserve(Some("Bearer nope"), &[[1; 64]])),
+        Some(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(
+        status(codes.reserve(Some("Bearer expired"), &[[1; 64]])),
+        Some(StatusCode::FORBIDDEN)
+    );
+
+    // A failed submission releases the code.
+    drop(codes.reserve(Some("Bearer abc123"), &[[1; 64]]).unwrap());
+    let reservation = codes.reserve(Some("Bearer abc123"), &[[1; 64]]).unwrap();
+    assert_eq!(
+        status(codes.reserve(Some("Bearer abc123"), &[[2; 64]])),
+        Some(StatusCode::FORBIDDEN)
+    );
+    let (_, pending) = reservation.pending(&[[1; 64]]).unwrap();
+    assert!(pending.pending);
+    let (id, record) = reservation.commit(vec![[1; 64]]).unwrap();
+    assert_eq!(id, code_id("abc123"));
+    assert_eq!(record.consumed_by, Some(vec![[1; 64]]));
+    assert!(!record.pending);
+
+    // Retrying the same submission is allowed, but nothing else is.
+    assert!(codes
+        .reserve(Some("Bearer abc123"), &[[1; 64]])
+        .unwrap()
+        .commit(vec![[1; 64]])
+        .is_none());
+    assert_eq!(
+        status(codes.reserve(Some("Bearer abc123"), &[[2; 64]])),
+        Some(StatusCode::FORBIDDEN)
+    );
+    assert!(codes.outstanding().is_empty());
+}
+
+#[test]
+fn test_revoked_codes_stay_revoked() {
+    let codes = AuthCodes::default();
+    codes.issue(code_id("abc123"), None).unwrap();
+    let reservation = codes.reserve(Some("Bearer abc123"), &[[1; 64]]).unwrap();
+    let record = codes.revoke(&code_id("abc123")).unwrap();
+    assert!(record.revoked);
+    assert!(codes.revoke(&code_id("abc123")).is_err());
+    assert!(reservation.commit(vec![[1; 64]]).is_none());
+
+    assert!(codes.issue(code_id("abc123"), None).is_none());
+    assert!(codes.reserve(Some("Bearer abc123"), &[[1; 64]]).is_err());
+    let codes = AuthCodes::from_records(vec![(code_id("abc123"), record)]);
+    assert!(codes.issue(code_id("abc123"), None).is_none());
+    assert!(codes.outstanding().is_empty());
+}
@@ -0,0 +1,249 @@
+use crate::storage::{BatchInfo, Encoding, SealedBatch};
+use crate::ReportTimestamp;
+use bytes::Bytes;
+use std::time::{Duration, SystemTime};
+use warp::http::header::{CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG, VARY};
+use warp::http::{Response, StatusCode};
+
+/// The entity tag of a sealed batch in an encoding: its quoted SHA-256 hash,
+/// suffixed with the encoding if it is compressed.
+pub(crate) fn etag(info: &BatchInfo, encoding: Option<Encoding>) -> String {
+    match encoding {
+        Some(encoding) => format!("\"{}-{}\"", hex::encode(info.sha256), encoding.name()),
+        None => format!("\"{}\"", hex::encode(info.sha256)),
+    }
+}
+
+/// Parse the items of an `Accept` or `Accept-Encoding` header along with
+/// their quality values, skipping malformed ones.
+fn qualities(header: &str) -> Vec<(&str, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().filter(|name| !name.is_empty())?;
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            Some((name, quality))
+        })
+        .collect()
+}
+
+/// Whether an `Accept` header prefers the binary form of a response,
+/// `application/octet-stream`, to its default JSON form.
+///
+/// Each type takes the quality of the most specific range that matches it,
+/// and the binary form is only picked if it has a higher quality than JSON,
+/// or the same quality from a more specific range.
+pub(crate) fn prefers_binary(accept: &str) -> bool {
+    let accepted = qualities(accept);
+    let quality = |media_type: &str| {
+        let quality_of = |range: &str| {
+            accepted
+                .iter()
+                .find(|(accepted, _)| accepted.eq_ignore_ascii_case(range))
+                .map(|(_, quality)| *quality)
+        };
+        let (kind, _) = media_type.split_at(media_type.find('/').unwrap_or(0));
+        quality_of(media_type)
+            .map(|quality| (quality, 2))
+            .or_else(|| quality_of(&format!("{}/*", kind)).map(|quality| (quality, 1)))
+            .or_else(|| quality_of("*/*").map(|quality| (quality, 0)))
+            .unwrap_or((0.0, 0))
+    };
+    let binary = quality("application/octet-stream");
+    binary.0 > 0.0 && binary > quality("application/json")
+}
+
+/// Pick the encoding to send out of those `available`, according to an
+/// `Accept-Encoding` header.
+///
+/// The encoding with the highest quality value wins, with ties going to the
+/// first one available.  Encodings with a quality of zero are never picked.
+pub(crate) fn negotiate(
+    accept_encoding: &str,
+    available: impl Iterator<Item = Encoding>,
+) -> Option<Encoding> {
+    let accepted = qualities(accept_encoding);
+    let quality = |encoding: Encoding| {
+        let quality_of = |name: &str| {
+            accepted
+                .iter()
+                .find(|(accepted, _)| accepted.eq_ignore_ascii_case(name))
+                .map(|(_, quality)| *quality)
+        };
+        quality_of(encoding.name())
+            .or_else(|| quality_of("*"))
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in available {
+        let quality = quality(encoding);
+        if quality > best.map_or(0.0, |(_, best)| best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether an `If-None-Match` header lists `etag`.
+///
+/// Weak tags are compared by their opaque part, as `If-None-Match` requires.
+pub(crate) fn none_match(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// The number of seconds a sealed batch can be cached for: until it expires.
+pub(crate) fn max_age(timeframe: ReportTimestamp) -> u64 {
+    let retention = Duration::from_secs(86400 * crate::OPTIONS.retention_days);
+    (timeframe.end_time() + retention)
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Respond with data derived from the sealed batch for `timeframe`, which
+/// never changes until the batch expires.
+pub(crate) fn immutable_response(timeframe: ReportTimestamp, body: Bytes) -> Response<Bytes> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(
+            CACHE_CONTROL,
+            format!("public, max-age={}, immutable", max_age(timeframe)),
+        )
+        .body(body)
+        .expect("response headers are valid")
+}
+
+/// Respond with a sealed batch, compressed if the client accepts one of its
+/// compressed forms, or with `304 Not Modified` if the client already holds
+/// it.
+pub(crate) fn batch_response(
+    batch: SealedBatch,
+    if_none_match: Option<&str>,
+    accept_encoding: Option<&str>,
+) -> Response<Bytes> {
+    let encoding = accept_encoding.and_then(|accept| negotiate(accept, batch.encoded.encodings()));
+    let etag = etag(&batch.info, encoding);
+    let cache_control = format!(
+        "public, max-age={}, immutable",
+        max_age(ReportTimestamp(batch.info.timestamp))
+    );
+    let mut response = Response::builder()
+        .header(ETAG, &etag)
+        .header(CACHE_CONTROL, cache_control)
+        .header(VARY, "Accept-Encoding");
+    if let (Some(key), Some(signature)) = (batch.info.public_key, batch.info.signature) {
+        response = response
+            .header("x-signing-key", hex::encode(key))
+            .header("x-signature", hex::encode(&signature[..]));
+    }
+    if if_none_match.map_or(false, |tags| none_match(tags, &etag)) {
+        response = response.status(StatusCode::NOT_MODIFIED);
+        return response
+            .body(Bytes::new())
+            .expect("batch response headers are valid");
+    }
+
+    response = response.header(CONTENT_TYPE, "application/octet-stream");
+    let body = match encoding.and_then(|encoding| Some((encoding, batch.encoded.get(encoding)?))) {
+        Some((encoding, compressed)) => {
+            response = response.header(CONTENT_ENCODING, encoding.name());
+            compressed
+        }
+        None => batch.bytes,
+    };
+    response
+        .body(body)
+        .expect("batch response headers are valid")
+}
+
+#[cfg(test)]
+fn sealed(bytes: &'static [u8]) -> SealedBatch {
+    let now = ReportTimestamp::now().unwrap();
+    SealedBatch {
+        bytes: Bytes::from_static(bytes),
+        info: BatchInfo::of(ReportTimestamp(now.0 - 1), bytes),
+        encoded: std::sync::Arc::new(crate::storage::Encoded::of(bytes)),
+    }
+}
+
+#[test]
+fn test_batch_response() {
+    let batch = sealed(b"batch");
+    let etag = etag(&batch.info, None);
+
+    let response = batch_response(sealed(b"batch"), None, None);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[ETAG], etag.as_str());
+    assert_eq!(response.body(), &batch.bytes);
+    let cache_control = response.headers()[CACHE_CONTROL].to_str().unwrap();
+    assert!(cache_control.ends_with(", immutable"));
+    let retention = 86400 * crate::OPTIONS.retention_days;
+    assert!(max_age(ReportTimestamp(batch.info.timestamp)) <= retention);
+
+    let tags = format!("\"other\", W/{}", etag);
+    let response = batch_response(sealed(b"batch"), Some(&tags), None);
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert!(response.body().is_empty());
+    assert_eq!(response.headers()[ETAG], etag.as_str());
+
+    let response = batch_response(sealed(b"batch"), Some("\"other\""), None);
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn test_compressed_batch_response() {
+    let bytes = b"report report report report report report report report";
+    let response = batch_response(sealed(bytes), None, Some("gzip;q=0.5, br"));
+    assert_eq!(response.headers()[CONTENT_ENCODING], "br");
+    assert!(response.body().len() < bytes.len());
+    let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+    assert!(etag.ends_with("-br\""));
+
+    // The compressed form has its own entity tag.
+    let response = batch_response(sealed(bytes), Some(&etag), Some("br"));
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    let response = batch_response(sealed(bytes), Some(&etag), None);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(CONTENT_ENCODING).is_none());
+
+    // Batches that do not compress are sent as they are.
+    let response = batch_response(sealed(b"x"), None, Some("gzip"));
+    assert!(response.headers().get(CONTENT_ENCODING).is_none());
+}
+
+#[test]
+fn test_negotiate() {
+    let all = || Encoding::ALL.iter().copied();
+    assert_eq!(negotiate("gzip, br", all()), Some(Encoding::Brotli));
+    assert_eq!(negotiate("gzip, br;q=0.5", all()), Some(Encoding::Gzip));
+    assert_eq!(negotiate("*", all()), Some(Encoding::Zstd));
+    assert_eq!(negotiate("*, zstd;q=0", all()), Some(Encoding::Brotli));
+    assert_eq!(negotiate("identity", all()), None);
+    assert_eq!(negotiate("gzip", std::iter::empty()), None);
+}
+
+#[test]
+fn test_prefers_binary() {
+    assert!(prefers_binary("application/octet-stream"));
+    assert!(prefers_binary(
+        "application/json;q=0.5, application/octet-stream"
+    ));
+    assert!(prefers_binary("application/octet-stream, */*"));
+    assert!(!prefers_binary("application/octet-stream;q=0"));
+    assert!(!prefers_binary("application/octet-stream;q=0, */*"));
+    assert!(!prefers_binary(
+        "application/octet-stream;q=0.5, application/json"
+    ));
+    assert!(!prefers_binary(
+        "application/octet-stream, application/json"
+    ));
+    assert!(!prefers_binary("*/*"));
+    assert!(!prefers_binary("application/*, application/json"));
+}
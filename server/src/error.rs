@@ -1,5 +1,7 @@
+use crate::rate_limit::RateLimited;
 use std::convert::Infallible;
-use warp::{http::StatusCode, Rejection, Reply};
+use warp::http::{header::RETRY_AFTER, StatusCode};
+use warp::{Rejection, Reply};
 
 pub(crate) mod context;
 
@@ -44,11 +46,17 @@ pub(crate) fn into_warp(report: impl Into<ErrReport>) -> Rejection {
 pub(crate) async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
     let message;
+    let mut retry_after = None;
 
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message =
-            format!("Error: {}\nNote: The supported endpoints by this server are `submit` and `get_reports/<timestamp>`\n", code);
+            format!("Error: {}\nNote: The supported endpoints by this server are `submit`, `submit_batch`, `get_reports/<timestamp>`, `get_reports/<from>..<to>`, `filter/<timestamp>`, `tcns/<timestamp>`, `batches` and `log` under `/<shard_id>/`, `submit?shards=<shard_ids>` and `keys`\n", code);
+    } else if let Some(limited) = err.find::<RateLimited>() {
+        code = StatusCode::TOO_MANY_REQUESTS;
+        message = format!("Error: {}\nToo many requests, try again later\n", code);
+        // Round up, so that a client retrying on time finds a token.
+        retry_after = Some(limited.retry_after.as_secs_f64().ceil() as u64);
     } else if let Some(report) = err.find::<ErrReport>() {
         code = report.0.context().status;
         message = format!("Error: {:?}\n", report);
@@ -59,5 +67,9 @@ pub(crate) async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infal
         message = "UNHANDLED_REJECTION\n".into();
     }
 
-    Ok(warp::reply::with_status(message, code))
+    let mut response = warp::reply::with_status(message, code).into_response();
+    if let Some(secs) = retry_after {
+        response.headers_mut().insert(RETRY_AFTER, secs.into());
+    }
+    Ok(response)
 }
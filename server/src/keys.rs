@@ -0,0 +1,193 @@
+use crate::auth::unix_now;
+use crate::error::ErrReport;
+use crate::serde_hex::as_hex;
+use ed25519_zebra::{PublicKeyBytes, SecretKey};
+use eyre::eyre;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A server signing key, as stored in a key file.
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    /// The hex-encoded 32-byte Ed25519 seed.
+    secret_key: String,
+    /// When the key starts being used, in seconds since the Unix epoch.
+    not_before: u64,
+    /// When the key stops being used, in seconds since the Unix epoch.
+    not_after: Option<u64>,
+}
+
+/// A published server signing key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct PublicKeyInfo {
+    #[serde(serialize_with = "as_hex")]
+    pub(crate) public_key: [u8; 32],
+    pub(crate) not_before: u64,
+    pub(crate) not_after: Option<u64>,
+}
+
+impl PublicKeyInfo {
+    /// Whether the key is used to sign at time `t`.
+    fn is_valid_at(&self, t: u64) -> bool {
+        self.not_before <= t && self.not_after.map_or(true, |not_after| t < not_after)
+    }
+
+    /// Check that `signature` is this key's signature of `message`.
+    #[cfg(test)]
+    pub(crate) fn verify(&self, signature: &[u8; 64], message: &[u8]) -> bool {
+        use ed25519_zebra::{PublicKey, Signature};
+        let key: Result<PublicKey, _> = self.public_key.try_into();
+        key.is_ok_and(|key| key.verify(&Signature::from(*signature), message).is_ok())
+    }
+}
+
+struct SigningKey {
+    secret: SecretKey,
+    info: PublicKeyInfo,
+}
+
+/// The server's signing keys.
+///
+/// Each key is valid for a period of time, and keys are rotated by adding a
+/// new key whose period overlaps the current one: from the new key's
+/// `not_before`, it is used to sign in place of the older key.  Every loaded
+/// key is published, so clients can pick up a new key before anything they
+/// see is signed with it, and can still check what an older key signed.
+#[derive(Default)]
+pub(crate) struct Keyring {
+    keys: Vec<SigningKey>,
+}
+
+impl Keyring {
+    /// Load every `*.key` file in `dir`.
+    pub(crate) fn load(dir: &Path) -> Result<Self, ErrReport> {
+        let mut keys = Vec::new();
+        for file in fs::read_dir(dir)
+            .map_err(|e| ErrReport::from(e).wrap_err(format!("Cannot read {}", dir.display())))?
+        {
+            let path = file?.path();
+            if path.extension() != Some("key".as_ref()) {
+                continue;
+            }
+            let key = fs::read(&path)
+                .map_err(ErrReport::from)
+                .and_then(|bytes| Ok(serde_json::from_slice::<KeyFile>(&bytes)?))
+                .and_then(SigningKey::from_file)
+                .map_err(|e| e.wrap_err(format!("Invalid key file {}", path.display())))?;
+            keys.push(key);
+        }
+        keys.sort_by_key(|key| key.info.not_before);
+        Ok(Self { keys })
+    }
+
+    /// The public halves of every key, oldest first.
+    pub(crate) fn public_keys(&self) -> Vec<PublicKeyInfo> {
+        self.keys.iter().map(|key| key.info).collect()
+    }
+
+    /// Sign `message` with the newest key that is valid now, returning the
+    /// key that was used along with the signature.
+    ///
+    /// Returns `None` if no key is valid.
+    pub(crate) fn sign(&self, message: &[u8]) -> Option<(PublicKeyInfo, [u8; 64])> {
+        self.sign_at(unix_now(), message)
+    }
+
+    fn sign_at(&self, t: u64, message: &[u8]) -> Option<(PublicKeyInfo, [u8; 64])> {
+        let key = self.keys.iter().rev().find(|key| key.info.is_valid_at(t))?;
+        Some((key.info, key.secret.sign(message).into()))
+    }
+}
+
+impl SigningKey {
+    fn from_file(file: KeyFile) -> Result<Self, ErrReport> {
+        let seed: [u8; 32] = hex::decode(&file.secret_key)?
+            .as_slice()
+            .try_into()
+            .map_err(|_| eyre!("Secret key must be 32 bytes"))?;
+        let secret = SecretKey::from(seed);
+        let info = PublicKeyInfo {
+            public_key: PublicKeyBytes::from(&secret).into(),
+            not_before: file.not_before,
+            not_after: file.not_after,
+        };
+        if info
+            .not_after
+            .map_or(false, |not_after| not_after <= info.not_before)
+        {
+            return Err(eyre!("Key expires before it becomes valid").into());
+        }
+        Ok(Self { secret, info })
+    }
+}
+
+/// Sign `message` with `sign`, returning the public key and signature to
+/// record in the signed value, which are both `None` if there is no key.
+pub(crate) fn signature(
+    message: &[u8],
+    sign: impl FnOnce(&[u8]) -> Option<(PublicKeyInfo, [u8; 64])>,
+) -> (Option<[u8; 32]>, Option<[u8; 64]>) {
+    match sign(message) {
+        Some((key, signature)) => (Some(key.public_key), Some(signature)),
+        None => (None, None),
+    }
+}
+
+/// Generate a new signing key valid from `not_before` for `valid_secs`, and
+/// write it to a new key file in `dir`, named after its public key.
+pub(crate) fn generate(
+    dir: &Path,
+    not_before: u64,
+    valid_secs: Option<u64>,
+) -> Result<(PathBuf, PublicKeyInfo), ErrReport> {
+    let secret = SecretKey::new(OsRng);
+    let file = KeyFile {
+        secret_key: hex::encode(<[u8; 32]>::from(secret)),
+        not_before,
+        not_after: valid_secs.map(|secs| not_before + secs),
+    };
+    let contents = serde_json::to_vec_pretty(&file)?;
+    let key = SigningKey::from_file(file)?;
+
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.key", &hex::encode(key.info.public_key)[..16]));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut out = options
+        .open(&path)
+        .map_err(|e| ErrReport::from(e).wrap_err(format!("Cannot create {}", path.display())))?;
+    out.write_all(&contents)?;
+    out.sync_all()?;
+    Ok((path, key.info))
+}
+
+#[test]
+fn test_key_rotation() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, old) = generate(dir.path(), 100, Some(100)).unwrap();
+    assert!(path.starts_with(dir.path()));
+    let (_, new) = generate(dir.path(), 150, None).unwrap();
+    assert!(generate(dir.path(), 10, Some(0)).is_err());
+    fs::write(dir.path().join("notes.txt"), "ignored").unwrap();
+
+    let keys = Keyring::load(dir.path()).unwrap();
+    assert_eq!(keys.public_keys(), vec![old, new]);
+    assert!(keys.sign_at(99, b"message").is_none());
+    let (key, signature) = keys.sign_at(120, b"message").unwrap();
+    assert_eq!(key, old);
+    assert!(old.verify(&signature, b"message"));
+    assert!(!new.verify(&signature, b"message"));
+    // The new key takes over as soon as it becomes valid, while the old key
+    // is still published.
+    assert_eq!(keys.sign_at(150, b"message").unwrap().0, new);
+    assert_eq!(keys.sign_at(1000, b"message").unwrap().0, new);
+
+    fs::write(dir.path().join("bad.key"), "{}").unwrap();
+    assert!(Keyring::load(dir.path()).is_err());
+}
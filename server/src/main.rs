@@ -1,22 +1,101 @@
+// `Option::is_some_and` and `is_none_or` are newer than the toolchains the
+// server supports, so `map_or` is used instead.
+#![allow(clippy::unnecessary_map_or)]
+
+use error::context::Status;
+use eyre::eyre;
 use futures::TryFutureExt;
 use once_cell::sync::Lazy;
+use rate_limit::RateLimiter;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::Arc;
 use structopt::StructOpt;
 use tcn::SignedReport;
 use tracing::info;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{prelude::*, EnvFilter};
+use warp::http::{header::CONTENT_TYPE, StatusCode};
 use warp::Filter;
 
+mod admin;
+mod auth;
+mod cache;
 mod error;
+mod keys;
+mod memo;
+mod merkle;
+mod rate_limit;
+mod receipt;
+mod report;
+mod serde_hex;
 mod shard;
 mod storage;
 mod timestamp;
 
-static STORAGE: Lazy<storage::Storage> = Lazy::new(storage::Storage::default);
-static OPTIONS: Lazy<Opt> = Lazy::new(Opt::from_args);
+static STORAGE: Lazy<storage::Storage> = Lazy::new(|| {
+    let storage = match OPTIONS.storage_dir {
+        Some(ref dir) => storage::Storage::new(
+            storage::DiskStore::open(dir)
+                .expect("could not open storage directory")
+                .with_cache_size(OPTIONS.disk_cache_mb << 20),
+        )
+        .expect("could not index stored reports"),
+        None => match OPTIONS.wal_path {
+            Some(ref path) => storage::Storage::new(
+                storage::MemoryStore::with_wal(path).expect("could not open write-ahead log"),
+            )
+            .expect("could not index stored reports"),
+            None => storage::Storage::default(),
+        },
+    };
+    let storage = match OPTIONS.export_dir {
+        Some(ref dir) => storage
+            .with_export(storage::Export::open(dir).expect("could not open export directory")),
+        None => storage,
+    };
+    let storage = if OPTIONS.serve_tcns {
+        storage.with_expanded_tcns()
+    } else {
+        storage
+    };
+    let storage = match OPTIONS.key_dir {
+        Some(ref dir) => {
+            storage.with_keys(keys::Keyring::load(dir).expect("could not load signing keys"))
+        }
+        None => storage,
+    };
+    let memo = memo::MemoPolicy::new(OPTIONS.allowed_memo_types.clone(), OPTIONS.max_memo_len);
+    let storage = storage.with_memo_validator(if OPTIONS.coepi_text_memos {
+        memo.require_coepi_text()
+    } else {
+        memo
+    });
+    if let Some(ref path) = OPTIONS.auth_codes {
+        let codes = std::fs::read_to_string(path).expect("could not read auth codes");
+        let imported = storage
+            .import_codes(codes.lines().map(str::trim).filter(|c| !c.is_empty()))
+            .expect("could not import auth codes");
+        info!(imported, "imported authorization codes");
+    }
+    if OPTIONS.require_auth_code {
+        storage.require_auth_code()
+    } else {
+        storage
+    }
+});
+static OPTIONS: Lazy<Opt> = Lazy::new(|| {
+    if cfg!(test) {
+        // The test harness's arguments are not ours, so use the defaults.
+        Opt::from_iter(&["tcn_server"])
+    } else {
+        Opt::from_args()
+    }
+});
 
 pub use shard::Shard;
-pub use timestamp::ReportTimestamp;
+pub use timestamp::{ReportTimestamp, TimestampRange};
 
 #[derive(Debug, StructOpt)]
 struct Opt {
@@ -26,9 +105,256 @@ struct Opt {
     /// when using the server in simulation mode, e.g., to 6s.
     #[structopt(short, long, default_value = "21600")]
     seconds_per_batch: u64,
+    /// The number of days to keep report batches for.
+    ///
+    /// The default matches the 14 day incubation window that clients report
+    /// history for.  Requests for older batches get `410 Gone`.
+    #[structopt(long, default_value = "14")]
+    retention_days: u64,
+    /// The TCK rotation interval used by clients, in seconds.
+    #[structopt(long, default_value = "900")]
+    tck_rotation_secs: u64,
+    /// The number of days of history that clients report upon infection.
+    #[structopt(long, default_value = "14")]
+    incubation_period_days: u64,
+    /// The maximum number of TCKs a single report may cover.
+    ///
+    /// Defaults to the number of TCK rotations in the incubation period.
+    #[structopt(long)]
+    max_tcks_per_report: Option<u32>,
+    /// The memo types accepted in submitted reports, separated by commas.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = "coepi-v1,covidwatch-v1",
+        parse(try_from_str = memo::parse_memo_type)
+    )]
+    allowed_memo_types: Vec<tcn::MemoType>,
+    /// The maximum length of a report's memo data, in bytes.
+    ///
+    /// If unset, memos may be as long as the report format allows.
+    #[structopt(long)]
+    max_memo_len: Option<usize>,
+    /// Reject CoEpi v1 memos that are not printable UTF-8 text.
+    ///
+    /// The CoEpi v1 encoding is not yet specified, so this is off by default.
+    #[structopt(long)]
+    coepi_text_memos: bool,
+    /// Require every submission to carry a one-time authorization code,
+    /// sent as `Authorization: Bearer <code>`.
+    #[structopt(long)]
+    require_auth_code: bool,
+    /// A file of issued authorization codes, one per line.
+    #[structopt(long, parse(from_os_str))]
+    auth_codes: Option<std::path::PathBuf>,
+    /// The most batch data returned for a range of timeframes, in MiB.
+    ///
+    /// Longer ranges are cut short, and a single larger batch must be fetched
+    /// on its own.
+    #[structopt(long, default_value = "64")]
+    max_range_mb: usize,
+    /// The maximum number of reports in a single `submit_batch` request.
+    #[structopt(long, default_value = "64")]
+    max_batch_reports: usize,
+    /// The number of submissions accepted from each IP address per minute.
+    ///
+    /// If unset, submissions are not rate limited.
+    #[structopt(long)]
+    submits_per_minute: Option<f64>,
+    /// The number of submissions accepted for each report authorization key
+    /// per minute.
+    ///
+    /// If unset, submissions are not rate limited by key.
+    #[structopt(long)]
+    submits_per_rvk_per_minute: Option<f64>,
+    /// The number of submissions that may be made at once before
+    /// `--submits-per-minute` and `--submits-per-rvk-per-minute` apply.
+    #[structopt(long, default_value = "10")]
+    submit_burst: u32,
+    /// The number of `get_reports` requests accepted from each IP address per
+    /// minute.
+    ///
+    /// If unset, downloads are not rate limited.
+    #[structopt(long)]
+    gets_per_minute: Option<f64>,
+    /// The number of `get_reports` requests that may be made at once before
+    /// `--gets-per-minute` applies.
+    #[structopt(long, default_value = "60")]
+    get_burst: u32,
     /// The socket address to bind to.
     #[structopt(short, long, default_value = "127.0.0.1:3030")]
     address: std::net::SocketAddr,
+    /// The socket address to serve the admin API on.
+    ///
+    /// The admin API issues and revokes authorization codes and has no
+    /// authentication of its own, so this must not be reachable by clients.
+    /// If unset, the admin API is disabled.
+    #[structopt(long)]
+    admin_address: Option<std::net::SocketAddr>,
+    /// A directory in which to persist reports.
+    ///
+    /// If unset, reports are kept in memory and lost when the server exits.
+    #[structopt(long, parse(from_os_str))]
+    storage_dir: Option<std::path::PathBuf>,
+    /// The most sealed batches read from `--storage-dir` to keep in memory,
+    /// in MiB.
+    #[structopt(long, default_value = "256")]
+    disk_cache_mb: usize,
+    /// A write-ahead log for reports, authorization codes and transparency
+    /// logs kept in memory.
+    ///
+    /// Every accepted report, every change to a code and every transparency
+    /// log entry is appended to the log before it is acknowledged, and the log
+    /// is replayed on startup.  Ignored when `--storage-dir` is set, since
+    /// on-disk batches are already durable.
+    #[structopt(long, parse(from_os_str))]
+    wal_path: Option<std::path::PathBuf>,
+    /// A directory to publish sealed batches to as static files.
+    ///
+    /// Each sealed batch is written to `{shard}/{timestamp}.bin`, alongside a
+    /// `{shard}/index.json` manifest, so that a static file server or object
+    /// store can serve downloads instead of this server.  The directory is
+    /// brought up to date on startup and whenever batches are sealed.
+    #[structopt(long, parse(from_os_str))]
+    export_dir: Option<std::path::PathBuf>,
+    /// A directory of server signing keys, one `*.key` file per key.
+    ///
+    /// Keys are created with the `keygen` subcommand, and published at
+    /// `GET /keys`.
+    #[structopt(long, parse(from_os_str))]
+    key_dir: Option<std::path::PathBuf>,
+    /// Serve the sorted TCNs of each sealed batch at `GET /{shard}/tcns/{n}`.
+    ///
+    /// This lets clients that cannot afford to expand reports into TCNs match
+    /// their observed TCNs without any cryptography, at the cost of computing
+    /// and keeping the TCNs of every retained batch.
+    #[structopt(long)]
+    serve_tcns: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Generate a new signing key in `--key-dir`.
+    ///
+    /// To rotate keys, generate a new key that becomes valid before the
+    /// current key expires and restart the server; the new key is published
+    /// right away and used to sign once it becomes valid.
+    Keygen {
+        /// When the key starts being used, in seconds since the Unix epoch.
+        ///
+        /// Defaults to now.
+        #[structopt(long)]
+        not_before: Option<u64>,
+        /// The number of days the key is used for.
+        ///
+        /// If unset, the key is used until a newer key becomes valid.
+        #[structopt(long)]
+        valid_days: Option<u64>,
+    },
+}
+
+impl Opt {
+    fn max_tcks_per_report(&self) -> u32 {
+        self.max_tcks_per_report.unwrap_or_else(|| {
+            (86400 * self.incubation_period_days / self.tck_rotation_secs.max(1)) as u32
+        })
+    }
+}
+
+/// The most shards a report can be submitted to in one request.
+const MAX_SHARDS_PER_SUBMIT: usize = 256;
+
+/// Read the shards a report is submitted to from the `shards` query
+/// parameter, a comma-separated list of shard ids.
+fn parse_shards(query: &HashMap<String, String>) -> Result<Vec<Shard>, error::ErrReport> {
+    let shards = query
+        .get("shards")
+        .ok_or(eyre!("Missing `shards` query parameter"))
+        .set_status(StatusCode::BAD_REQUEST)?;
+    let shards = shard::parse_list(shards)
+        .map_err(|e| error::ErrReport::from(e).wrap_err("Invalid shard id"))
+        .set_status(StatusCode::BAD_REQUEST)?;
+    if shards.len() > MAX_SHARDS_PER_SUBMIT {
+        return Err(eyre!(
+            "Reports can be submitted to at most {} shards at once",
+            MAX_SHARDS_PER_SUBMIT
+        ))
+        .set_status(StatusCode::BAD_REQUEST);
+    }
+    Ok(shards)
+}
+
+/// Parse a hex-encoded SHA-256 hash.
+fn parse_hash(input: &str) -> Result<[u8; 32], error::ErrReport> {
+    let mut hash = [0; 32];
+    hex::decode_to_slice(input, &mut hash)
+        .map_err(|e| error::ErrReport::from(e).wrap_err("Invalid SHA-256 hash"))
+        .set_status(StatusCode::BAD_REQUEST)?;
+    Ok(hash)
+}
+
+/// The outcome of one report in a `submit_batch` request.
+#[derive(Serialize)]
+struct BatchResult {
+    status: u16,
+    message: String,
+    /// The receipt for the report, if it was accepted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipt: Option<receipt::Receipt>,
+}
+
+/// Summarize the outcome of each report in a batch, answering with the
+/// status of the first rejected report if the batch was not stored.
+fn batch_summary(results: Vec<Result<storage::Saved, error::ErrReport>>) -> impl warp::Reply {
+    let mut status = StatusCode::OK;
+    let results: Vec<_> = results
+        .into_iter()
+        .map(|result| {
+            let (code, message, receipt) = match result {
+                Ok(storage::Saved::Stored(receipt)) => {
+                    (StatusCode::OK, "report saved".to_string(), Some(receipt))
+                }
+                Ok(storage::Saved::Duplicate(receipt)) => (
+                    StatusCode::OK,
+                    "report was already saved".to_string(),
+                    Some(receipt),
+                ),
+                Ok(storage::Saved::Withheld) => (
+                    StatusCode::OK,
+                    "report is valid, but was not saved because the batch was rejected".to_string(),
+                    None,
+                ),
+                Err(e) => (e.0.context().status, format!("{:#}", e), None),
+            };
+            if status == StatusCode::OK {
+                status = code;
+            }
+            BatchResult {
+                status: code.as_u16(),
+                message,
+                receipt,
+            }
+        })
+        .collect();
+    warp::reply::with_status(warp::reply::json(&results), status)
+}
+
+/// Stream batches one after another, each prefixed by its timestamp and
+/// length:
+///
+/// ```text
+/// [timestamp: u64 LE][length: u32 LE][batch]
+/// ```
+fn framed_batches(batches: Vec<(ReportTimestamp, bytes::Bytes)>) -> warp::hyper::Body {
+    let chunks = batches.into_iter().flat_map(|(timeframe, batch)| {
+        let mut header = Vec::with_capacity(12);
+        header.extend_from_slice(&timeframe.0.to_le_bytes());
+        header.extend_from_slice(&(batch.len() as u32).to_le_bytes());
+        vec![Ok::<_, Infallible>(header.into()), Ok(batch)]
+    });
+    warp::hyper::Body::wrap_stream(futures::stream::iter(chunks))
 }
 
 #[tokio::main]
@@ -48,30 +374,288 @@ async fn main() {
 
     info!(options = ?*OPTIONS);
 
+    if let Some(Command::Keygen {
+        not_before,
+        valid_days,
+    }) = OPTIONS.command
+    {
+        let dir = OPTIONS
+            .key_dir
+            .as_ref()
+            .expect("--key-dir is required to generate a key");
+        let not_before = not_before.unwrap_or_else(auth::unix_now);
+        let (path, key) = keys::generate(dir, not_before, valid_days.map(|days| days * 86400))
+            .expect("could not generate signing key");
+        println!("{} {}", path.display(), hex::encode(key.public_key));
+        return;
+    }
+
     let storage = &*STORAGE;
+    tokio::spawn(storage.seal_periodically());
+
+    let submit_limiter = OPTIONS
+        .submits_per_minute
+        .map(|rate| Arc::new(RateLimiter::new(rate, OPTIONS.submit_burst)));
+    let rvk_limiter = OPTIONS
+        .submits_per_rvk_per_minute
+        .map(|rate| Arc::new(RateLimiter::new(rate, OPTIONS.submit_burst)));
+    let get_limiter = OPTIONS
+        .gets_per_minute
+        .map(|rate| Arc::new(RateLimiter::new(rate, OPTIONS.get_burst)));
+
     let submit = warp::path!(Shard / "submit")
         .and(warp::filters::method::post())
+        .and(rate_limit::by_remote_address(submit_limiter.clone()))
+        .and(warp::filters::body::content_length_limit(1024 * 2))
+        .and(warp::filters::body::bytes())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then({
+            let rvk_limiter = rvk_limiter.clone();
+            move |shard, body: bytes::Bytes, authorization: Option<String>| {
+                let rvk_limiter = rvk_limiter.clone();
+                async move {
+                    let report = SignedReport::read(body.as_ref()).map_err(error::into_warp)?;
+                    if let Some(limiter) = rvk_limiter {
+                        let rvk = report::ReportFields::of(&report).rvk;
+                        limiter.check(rvk).map_err(warp::reject::custom)?;
+                    }
+                    storage
+                        .save(shard, report, authorization.as_deref())
+                        .map_err(|e| e.wrap_err("Failed to save report"))
+                        .map_err(error::into_warp)
+                        .await
+                }
+            }
+        })
+        .map(|receipt: receipt::Receipt| warp::reply::json(&receipt));
+
+    let submit_batch = warp::path!(Shard / "submit_batch")
+        .and(warp::filters::method::post())
+        .and(rate_limit::by_remote_address(submit_limiter.clone()))
+        .and(warp::filters::body::content_length_limit(
+            1024 * 2 * OPTIONS.max_batch_reports as u64,
+        ))
+        .and(warp::filters::body::bytes())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then({
+            let rvk_limiter = rvk_limiter.clone();
+            move |shard, body: bytes::Bytes, authorization: Option<String>| {
+                let rvk_limiter = rvk_limiter.clone();
+                async move {
+                    let reports = report::read_batch(&body, OPTIONS.max_batch_reports)
+                        .map_err(error::into_warp)?;
+                    if let Some(limiter) = rvk_limiter {
+                        let rvks: HashSet<_> = reports
+                            .iter()
+                            .map(|report| report::ReportFields::of(report).rvk)
+                            .collect();
+                        for rvk in rvks {
+                            limiter.check(rvk).map_err(warp::reject::custom)?;
+                        }
+                    }
+                    storage
+                        .save_batch(shard, reports, authorization.as_deref())
+                        .map_err(|e| e.wrap_err("Failed to save reports"))
+                        .map_err(error::into_warp)
+                        .await
+                }
+            }
+        })
+        .map(batch_summary);
+
+    let submit_to_shards = warp::path!("submit")
+        .and(warp::filters::method::post())
+        .and(rate_limit::by_remote_address(submit_limiter.clone()))
+        .and(warp::query::<HashMap<String, String>>())
         .and(warp::filters::body::content_length_limit(1024 * 2))
         .and(warp::filters::body::bytes())
-        .and_then(move |shard, body: bytes::Bytes| async move {
-            let report = SignedReport::read(body.as_ref()).map_err(error::into_warp)?;
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then({
+            let rvk_limiter = rvk_limiter.clone();
+            move |query: HashMap<String, String>,
+                  body: bytes::Bytes,
+                  authorization: Option<String>| {
+                let rvk_limiter = rvk_limiter.clone();
+                async move {
+                    let shards = parse_shards(&query).map_err(error::into_warp)?;
+                    let report = SignedReport::read(body.as_ref()).map_err(error::into_warp)?;
+                    if let Some(limiter) = rvk_limiter {
+                        let rvk = report::ReportFields::of(&report).rvk;
+                        limiter.check(rvk).map_err(warp::reject::custom)?;
+                    }
+                    storage
+                        .save_to_shards(&shards, report, authorization.as_deref())
+                        .map_err(|e| e.wrap_err("Failed to save report"))
+                        .map_err(error::into_warp)
+                        .await
+                }
+            }
+        })
+        .map(|receipts: Vec<receipt::Receipt>| warp::reply::json(&receipts));
+
+    let get_range = warp::path!(Shard / "get_reports" / TimestampRange)
+        .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter.clone()))
+        .and_then(move |shard, range: TimestampRange| {
             storage
-                .save(shard, report)
-                .map_err(|e| e.wrap_err("Failed to save report"))
+                .get_range(shard, range.from, range.to)
+                .map_err(|e| e.wrap_err("Failed to retrieve reports"))
                 .map_err(error::into_warp)
-                .await
+        })
+        .map(|(batches, until): (Vec<_>, ReportTimestamp)| {
+            warp::http::Response::builder()
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header("x-next-timestamp", until.0)
+                .body(framed_batches(batches))
         });
 
-    let get = warp::path!(Shard / "get_reports" / ReportTimestamp)
+    let batches = warp::path!(Shard / "batches")
         .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter.clone()))
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(move |shard, accept: Option<String>| async move {
+            let batches = storage
+                .batches(shard)
+                .map_err(|e| e.wrap_err("Failed to list batches"))
+                .map_err(error::into_warp)
+                .await?;
+            let binary = accept.map_or(false, |accept| cache::prefers_binary(&accept));
+            let (content_type, body) = if binary {
+                (
+                    "application/octet-stream",
+                    storage::encode_manifest(&batches),
+                )
+            } else {
+                let json = serde_json::to_vec(&batches).map_err(error::into_warp)?;
+                ("application/json", json)
+            };
+            let mut response = warp::http::Response::builder().header(CONTENT_TYPE, content_type);
+            // The manifest lists each batch's hash, so a signed manifest lets
+            // clients check batches fetched from untrusted mirrors.
+            if let Some((key, signature)) = storage.sign(&storage::manifest_message(shard, &body)) {
+                response = response
+                    .header("x-signing-key", hex::encode(key.public_key))
+                    .header("x-signature", hex::encode(&signature[..]));
+            }
+            Ok::<_, warp::Rejection>(response.body(body))
+        });
+
+    let filter = warp::path!(Shard / "filter" / ReportTimestamp)
+        .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter.clone()))
         .and_then(move |shard, timeframe| {
             storage
-                .get(shard, timeframe)
-                .map_err(|e| e.wrap_err("Failed to retrieve reports"))
+                .filter(shard, timeframe)
+                .map_ok(move |filter| cache::immutable_response(timeframe, filter))
+                .map_err(|e| e.wrap_err("Failed to retrieve filter"))
+                .map_err(error::into_warp)
+        });
+
+    let tcns = warp::path!(Shard / "tcns" / ReportTimestamp)
+        .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter.clone()))
+        .and_then(move |shard, timeframe| {
+            storage
+                .tcns(shard, timeframe)
+                .map_ok(move |tcns| cache::immutable_response(timeframe, tcns))
+                .map_err(|e| e.wrap_err("Failed to retrieve TCNs"))
+                .map_err(error::into_warp)
+        });
+
+    let log_head = warp::path!(Shard / "log")
+        .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter.clone()))
+        .and_then(move |shard| {
+            storage
+                .tree_head(shard)
+                .map_ok(|head| warp::reply::json(&head))
+                .map_err(|e| e.wrap_err("Failed to compute tree head"))
+                .map_err(error::into_warp)
+        });
+
+    let log_entries = warp::path!(Shard / "log" / "entries")
+        .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter.clone()))
+        .and_then(move |shard| {
+            storage
+                .log(shard)
+                .map_ok(|entries| warp::reply::json(&entries))
+                .map_err(|e| e.wrap_err("Failed to read log"))
                 .map_err(error::into_warp)
         });
 
-    warp::serve(submit.or(get).recover(error::handle_rejection))
-        .run(OPTIONS.address)
-        .await;
+    let consistency = warp::path!(Shard / "log" / "consistency" / u64 / u64)
+        .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter.clone()))
+        .and_then(move |shard, first, second| {
+            storage
+                .consistency_proof(shard, first, second)
+                .map_ok(|proof| warp::reply::json(&proof))
+                .map_err(|e| e.wrap_err("Failed to prove consistency"))
+                .map_err(error::into_warp)
+        });
+
+    let inclusion = warp::path!(Shard / "log" / "inclusion" / ReportTimestamp / String)
+        .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter.clone()))
+        .and_then(move |shard, timeframe, report_sha256: String| async move {
+            let report_sha256 = parse_hash(&report_sha256).map_err(error::into_warp)?;
+            storage
+                .inclusion_proof(shard, timeframe, report_sha256)
+                .map_ok(|proof| warp::reply::json(&proof))
+                .map_err(|e| e.wrap_err("Failed to prove inclusion"))
+                .map_err(error::into_warp)
+                .await
+        });
+
+    let keys = warp::path!("keys")
+        .and(warp::filters::method::get())
+        .map(move || warp::reply::json(&storage.public_keys()));
+
+    let get = warp::path!(Shard / "get_reports" / ReportTimestamp)
+        .and(warp::filters::method::get())
+        .and(rate_limit::by_remote_address(get_limiter))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and_then(
+            move |shard,
+                  timeframe,
+                  if_none_match: Option<String>,
+                  accept_encoding: Option<String>| async move {
+                let batch = storage
+                    .get(shard, timeframe)
+                    .map_err(|e| e.wrap_err("Failed to retrieve reports"))
+                    .map_err(error::into_warp)
+                    .await?;
+                Ok::<_, warp::Rejection>(cache::batch_response(
+                    batch,
+                    if_none_match.as_deref(),
+                    accept_encoding.as_deref(),
+                ))
+            },
+        );
+
+    if let Some(address) = OPTIONS.admin_address {
+        let admin = admin::routes(storage).recover(error::handle_rejection);
+        tokio::spawn(warp::serve(admin).run(address));
+    }
+
+    warp::serve(
+        submit
+            .or(submit_batch)
+            .or(submit_to_shards)
+            .or(get)
+            .or(get_range)
+            .or(batches)
+            .or(filter)
+            .or(tcns)
+            .or(keys)
+            .or(log_head)
+            .or(log_entries)
+            .or(consistency)
+            .or(inclusion)
+            .recover(error::handle_rejection),
+    )
+    .run(OPTIONS.address)
+    .await;
 }
@@ -0,0 +1,113 @@
+use crate::error::{context::Status, ErrReport};
+use eyre::eyre;
+use tcn::MemoType;
+use warp::http::StatusCode;
+
+/// Checks the memo of a verified report before it is stored.
+pub(crate) trait MemoValidator: Send + Sync {
+    fn validate(&self, memo_type: MemoType, memo_data: &[u8]) -> Result<(), ErrReport>;
+}
+
+/// The deployment's memo policy: which memo types are accepted, how long memo
+/// data may be, and, optionally, the form of CoEpi v1 memos.
+#[derive(Debug, Clone)]
+pub(crate) struct MemoPolicy {
+    allowed_types: Vec<MemoType>,
+    /// The longest memo accepted, if shorter than the report format allows.
+    max_len: Option<usize>,
+    coepi_text: bool,
+}
+
+impl Default for MemoPolicy {
+    fn default() -> Self {
+        Self::new(vec![MemoType::CoEpiV1, MemoType::CovidWatchV1], None)
+    }
+}
+
+impl MemoPolicy {
+    pub(crate) fn new(allowed_types: Vec<MemoType>, max_len: Option<usize>) -> Self {
+        Self {
+            allowed_types,
+            max_len,
+            coepi_text: false,
+        }
+    }
+
+    /// Require CoEpi v1 memos to be printable UTF-8 text.
+    pub(crate) fn require_coepi_text(self) -> Self {
+        Self {
+            coepi_text: true,
+            ..self
+        }
+    }
+}
+
+impl MemoValidator for MemoPolicy {
+    fn validate(&self, memo_type: MemoType, memo_data: &[u8]) -> Result<(), ErrReport> {
+        if !self.allowed_types.contains(&memo_type) {
+            return Err(eyre!(
+                "Memo type {:?} is not accepted by this server",
+                memo_type
+            ))
+            .set_status(StatusCode::BAD_REQUEST);
+        }
+        match self.max_len {
+            Some(max_len) if memo_data.len() > max_len => {
+                return Err(eyre!(
+                    "Memo is {} bytes long, but at most {} are allowed",
+                    memo_data.len(),
+                    max_len
+                ))
+                .set_status(StatusCode::BAD_REQUEST);
+            }
+            _ => {}
+        }
+        match memo_type {
+            MemoType::CoEpiV1 if self.coepi_text => validate_coepi_text(memo_data),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check that a CoEpi v1 symptom report is printable UTF-8 text.
+///
+/// The `tcn` crate leaves the CoEpi v1 encoding to be determined, so this is
+/// only a deployment's guess at the format, and is off unless enabled.  An
+/// empty memo is a report without symptom data.
+fn validate_coepi_text(memo_data: &[u8]) -> Result<(), ErrReport> {
+    let text = std::str::from_utf8(memo_data)
+        .map_err(|e| ErrReport::from(e).wrap_err("CoEpi v1 memo is not valid UTF-8"))
+        .set_status(StatusCode::BAD_REQUEST)?;
+    if text.chars().any(char::is_control) {
+        return Err(eyre!("CoEpi v1 memo contains control characters"))
+            .set_status(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
+}
+
+/// Parse a memo type name, as used on the command line.
+pub(crate) fn parse_memo_type(name: &str) -> Result<MemoType, String> {
+    match name {
+        "coepi-v1" => Ok(MemoType::CoEpiV1),
+        "covidwatch-v1" => Ok(MemoType::CovidWatchV1),
+        _ => Err(format!(
+            "unknown memo type {:?}, expected `coepi-v1` or `covidwatch-v1`",
+            name
+        )),
+    }
+}
+
+#[test]
+fn test_memo_policy() {
+    let policy = MemoPolicy::new(vec![MemoType::CoEpiV1], Some(8));
+    assert!(policy.validate(MemoType::CoEpiV1, b"").is_ok());
+    assert!(policy.validate(MemoType::CoEpiV1, b"cough").is_ok());
+    assert!(policy.validate(MemoType::CoEpiV1, b"\xff\xfe").is_ok());
+    assert!(policy.validate(MemoType::CoEpiV1, b"too long!").is_err());
+    assert!(policy.validate(MemoType::CovidWatchV1, b"").is_err());
+
+    let policy = policy.require_coepi_text();
+    assert!(policy.validate(MemoType::CoEpiV1, b"cough").is_ok());
+    assert!(policy.validate(MemoType::CoEpiV1, b"\xff\xfe").is_err());
+    assert!(policy.validate(MemoType::CoEpiV1, b"a\x00b").is_err());
+}
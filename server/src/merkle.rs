@@ -0,0 +1,209 @@
+use sha2::{Digest, Sha256};
+
+pub(crate) type Hash = [u8; 32];
+
+fn sha256(parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.input(part);
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+/// The hash of a leaf holding `data`, as defined by RFC 6962 along with the
+/// rest of the tree.
+pub(crate) fn leaf_hash(data: &[u8]) -> Hash {
+    sha256(&[&[0], data])
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    sha256(&[&[1], left, right])
+}
+
+/// The largest power of two smaller than `n`, for `n > 1`.
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/// The root hash of the tree with the given leaf hashes.
+pub(crate) fn root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => sha256(&[]),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+/// The audit path proving that leaf `index` is in the tree with the given
+/// leaf hashes, ordered from the leaf up.
+pub(crate) fn inclusion_proof(index: usize, leaves: &[Hash]) -> Vec<Hash> {
+    let n = leaves.len();
+    if n <= 1 {
+        return Vec::new();
+    }
+    let k = split(n);
+    if index < k {
+        let mut proof = inclusion_proof(index, &leaves[..k]);
+        proof.push(root(&leaves[k..]));
+        proof
+    } else {
+        let mut proof = inclusion_proof(index - k, &leaves[k..]);
+        proof.push(root(&leaves[..k]));
+        proof
+    }
+}
+
+/// The proof that the tree of the first `size` leaves is a prefix of the
+/// tree with the given leaf hashes, for `0 < size <= leaves.len()`.
+pub(crate) fn consistency_proof(size: usize, leaves: &[Hash]) -> Vec<Hash> {
+    fn subproof(m: usize, leaves: &[Hash], complete: bool) -> Vec<Hash> {
+        let n = leaves.len();
+        if m == n {
+            return if complete {
+                Vec::new()
+            } else {
+                vec![root(leaves)]
+            };
+        }
+        let k = split(n);
+        if m <= k {
+            let mut proof = subproof(m, &leaves[..k], complete);
+            proof.push(root(&leaves[k..]));
+            proof
+        } else {
+            let mut proof = subproof(m - k, &leaves[k..], false);
+            proof.push(root(&leaves[..k]));
+            proof
+        }
+    }
+    subproof(size, leaves, true)
+}
+
+/// Check an inclusion proof, following RFC 9162 section 2.1.3.2.
+#[cfg(test)]
+pub(crate) fn verify_inclusion(
+    index: usize,
+    size: usize,
+    leaf: Hash,
+    proof: &[Hash],
+    root: Hash,
+) -> bool {
+    if index >= size {
+        return false;
+    }
+    let (mut f, mut s) = (index, size - 1);
+    let mut r = leaf;
+    for p in proof {
+        if s == 0 {
+            return false;
+        }
+        if f & 1 == 1 || f == s {
+            r = node_hash(p, &r);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    s == 0 && r == root
+}
+
+/// Check a consistency proof, following RFC 9162 section 2.1.4.2.
+#[cfg(test)]
+pub(crate) fn verify_consistency(
+    first: usize,
+    second: usize,
+    first_root: Hash,
+    second_root: Hash,
+    proof: &[Hash],
+) -> bool {
+    if first == 0 || first > second {
+        return false;
+    }
+    if first == second {
+        return proof.is_empty() && first_root == second_root;
+    }
+    let mut path = proof.to_vec();
+    if first.is_power_of_two() {
+        path.insert(0, first_root);
+    }
+    let (mut f, mut s) = (first - 1, second - 1);
+    while f & 1 == 1 {
+        f >>= 1;
+        s >>= 1;
+    }
+    let (mut fr, mut sr) = match path.first() {
+        Some(hash) => (*hash, *hash),
+        None => return false,
+    };
+    for c in &path[1..] {
+        if s == 0 {
+            return false;
+        }
+        if f & 1 == 1 || f == s {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    fr == first_root && sr == second_root && s == 0
+}
+
+#[test]
+fn test_merkle_proofs() {
+    let leaves: Vec<_> = (0..20u8).map(|i| leaf_hash(&[i])).collect();
+    assert_eq!(
+        root(&leaves[..3]),
+        node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2])
+    );
+
+    for n in 1..=leaves.len() {
+        let tree = &leaves[..n];
+        for index in 0..n {
+            let proof = inclusion_proof(index, tree);
+            assert!(verify_inclusion(index, n, tree[index], &proof, root(tree)));
+            assert!(!verify_inclusion(
+                index,
+                n,
+                leaf_hash(b"x"),
+                &proof,
+                root(tree)
+            ));
+        }
+        for m in 1..=n {
+            let proof = consistency_proof(m, tree);
+            assert!(verify_consistency(
+                m,
+                n,
+                root(&tree[..m]),
+                root(tree),
+                &proof
+            ));
+            if m < n {
+                assert!(!verify_consistency(
+                    m,
+                    n,
+                    root(&leaves[1..=m]),
+                    root(tree),
+                    &proof
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::{Filter, Rejection};
+
+/// The number of keys tracked before buckets that have refilled are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+/// How often buckets may be pruned, since pruning visits every bucket.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A request was rejected because its key ran out of tokens.
+#[derive(Debug)]
+pub(crate) struct RateLimited {
+    /// How long until the key has a token again.
+    pub(crate) retry_after: Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+/// A token-bucket rate limiter with one bucket per key.
+///
+/// Each bucket holds up to `burst` tokens and refills at `rate` tokens per
+/// second; a request takes one token.  Buckets that have refilled completely
+/// are indistinguishable from new ones, so they are dropped periodically once
+/// many keys are tracked.
+pub(crate) struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets<K>>,
+}
+
+struct Buckets<K> {
+    by_key: HashMap<K, Bucket>,
+    pruned: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Allow `per_minute` requests per minute for each key, in bursts of up
+    /// to `burst` requests.
+    pub(crate) fn new(per_minute: f64, burst: u32) -> Self {
+        Self {
+            rate: per_minute / 60.0,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token from the bucket for `key`.
+    pub(crate) fn check(&self, key: K) -> Result<(), RateLimited> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: K, now: Instant) -> Result<(), RateLimited> {
+        let (rate, burst) = (self.rate, self.burst);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.by_key.len() >= PRUNE_THRESHOLD
+            && now.saturating_duration_since(buckets.pruned) >= PRUNE_INTERVAL
+        {
+            buckets.by_key.retain(|_, bucket| {
+                bucket.refill(now, rate, burst);
+                bucket.tokens < burst
+            });
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.by_key.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.refill(now, rate, burst);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = if rate > 0.0 {
+            (1.0 - bucket.tokens) / rate
+        } else {
+            f64::from(u32::MAX)
+        };
+        Err(RateLimited {
+            retry_after: Duration::from_secs_f64(wait),
+        })
+    }
+}
+
+/// A filter that rejects requests with [`RateLimited`] once the key
+/// extracted by `key` has used up its tokens in `limiter`.
+///
+/// Without a limiter, or for requests without a key, the filter lets
+/// everything through.
+pub(crate) fn limit<K, F>(
+    limiter: Option<Arc<RateLimiter<K>>>,
+    key: F,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    F: Filter<Extract = (Option<K>,), Error = Infallible> + Clone,
+{
+    key.and_then(move |key: Option<K>| {
+        let limiter = limiter.clone();
+        async move {
+            match (limiter, key) {
+                (Some(limiter), Some(key)) => limiter.check(key).map_err(warp::reject::custom),
+                _ => Ok(()),
+            }
+        }
+    })
+    .untuple_one()
+}
+
+/// Limit requests by their remote IP address.
+///
+/// Behind a reverse proxy every request comes from the proxy's address, so
+/// the limit applies to all clients together.
+pub(crate) fn by_remote_address(
+    limiter: Option<Arc<RateLimiter<IpAddr>>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    limit(
+        limiter,
+        warp::addr::remote().map(|remote: Option<SocketAddr>| remote.map(|addr| addr.ip())),
+    )
+}
+
+#[test]
+fn test_token_bucket() {
+    let limiter = RateLimiter::new(60.0, 2);
+    let start = Instant::now();
+    assert!(limiter.check_at("a", start).is_ok());
+    assert!(limiter.check_at("a", start).is_ok());
+    let limited = limiter.check_at("a", start).unwrap_err();
+    assert_eq!(limited.retry_after, Duration::from_secs(1));
+    // Other keys have their own bucket.
+    assert!(limiter.check_at("b", start).is_ok());
+
+    let later = start + Duration::from_millis(500);
+    assert_eq!(
+        limiter.check_at("a", later).unwrap_err().retry_after,
+        Duration::from_millis(500)
+    );
+    assert!(limiter
+        .check_at("a", start + Duration::from_secs(1))
+        .is_ok());
+}
+
+#[test]
+fn test_buckets_are_pruned_periodically() {
+    let limiter = RateLimiter::new(60.0, 2);
+    let start = Instant::now();
+    for key in 0..PRUNE_THRESHOLD {
+        limiter.check_at(key, start).unwrap();
+    }
+    let count = || limiter.buckets.lock().unwrap().by_key.len();
+
+    // Every bucket has refilled, but pruning waits for the interval.
+    limiter
+        .check_at(PRUNE_THRESHOLD, start + Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(count(), PRUNE_THRESHOLD + 1);
+    limiter.check_at(0, start + PRUNE_INTERVAL).unwrap();
+    assert_eq!(count(), 1);
+}
+
+#[tokio::test]
+async fn test_rate_limit_filter() {
+    use warp::http::StatusCode;
+
+    let limiter = Arc::new(RateLimiter::new(1.0, 1));
+    let client = warp::header::headers_cloned().map(|headers: warp::http::HeaderMap| {
+        headers
+            .get("x-client")
+            .map(|client| client.as_bytes().to_vec())
+    });
+    let route = limit(Some(limiter), client)
+        .map(warp::reply)
+        .recover(crate::error::handle_rejection);
+    let request = |client: &str| {
+        warp::test::request()
+            .header("x-client", client)
+            .reply(&route)
+    };
+
+    assert_eq!(request("a").await.status(), StatusCode::OK);
+    let response = request("a").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "60");
+    assert_eq!(request("b").await.status(), StatusCode::OK);
+    // Requests without a key are not limited.
+    let response = warp::test::request().reply(&route).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
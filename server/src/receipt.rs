@@ -0,0 +1,103 @@
+use crate::keys::{self, PublicKeyInfo};
+use crate::serde_hex::{as_hex, option_as_hex};
+use crate::{ReportTimestamp, Shard};
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use tcn::SignedReport;
+
+/// Proof that the server accepted a report into a batch.
+///
+/// The report can later be found in the published batch by hashing each of
+/// the batch's reports.  The receipt is signed if the server has a signing
+/// key, over the bytes returned by [`Receipt::message`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Receipt {
+    /// The SHA-256 hash of the serialized report.
+    #[serde(serialize_with = "as_hex")]
+    pub(crate) report_sha256: [u8; 32],
+    #[serde(serialize_with = "shard_id")]
+    pub(crate) shard: Shard,
+    /// The batch the report was accepted into.
+    #[serde(serialize_with = "timestamp_index")]
+    pub(crate) timestamp: ReportTimestamp,
+    /// When the server issued the receipt, in seconds since the Unix epoch.
+    pub(crate) received_at: u64,
+    #[serde(
+        serialize_with = "option_as_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) public_key: Option<[u8; 32]>,
+    #[serde(
+        serialize_with = "option_as_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) signature: Option<[u8; 64]>,
+}
+
+fn shard_id<S: Serializer>(shard: &Shard, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(shard.0)
+}
+
+fn timestamp_index<S: Serializer>(
+    timestamp: &ReportTimestamp,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(timestamp.0)
+}
+
+/// The SHA-256 hash of a report as it is serialized in a batch.
+pub(crate) fn report_hash(report: &SignedReport) -> [u8; 32] {
+    let mut bytes = Vec::new();
+    report
+        .write(&mut bytes)
+        .expect("writing to a Vec cannot fail");
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(&bytes));
+    hash
+}
+
+impl Receipt {
+    /// An unsigned receipt for `report`.
+    pub(crate) fn new(
+        report: &SignedReport,
+        shard: Shard,
+        timestamp: ReportTimestamp,
+        received_at: u64,
+    ) -> Self {
+        Self {
+            report_sha256: report_hash(report),
+            shard,
+            timestamp,
+            received_at,
+            public_key: None,
+            signature: None,
+        }
+    }
+
+    /// The signed message:
+    ///
+    /// ```text
+    /// "tcn-receipt-v1"[report_sha256: 32][shard: u64 LE][timestamp: u64 LE][received_at: u64 LE]
+    /// ```
+    pub(crate) fn message(&self) -> Vec<u8> {
+        let mut message = b"tcn-receipt-v1".to_vec();
+        message.extend_from_slice(&self.report_sha256);
+        message.extend_from_slice(&self.shard.0.to_le_bytes());
+        message.extend_from_slice(&self.timestamp.0.to_le_bytes());
+        message.extend_from_slice(&self.received_at.to_le_bytes());
+        message
+    }
+
+    /// Sign the receipt with `sign`, leaving it unsigned if there is no key.
+    pub(crate) fn sign(
+        self,
+        sign: impl FnOnce(&[u8]) -> Option<(PublicKeyInfo, [u8; 64])>,
+    ) -> Self {
+        let (public_key, signature) = keys::signature(&self.message(), sign);
+        Self {
+            public_key,
+            signature,
+            ..self
+        }
+    }
+}
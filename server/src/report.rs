@@ -0,0 +1,108 @@
+use crate::error::{context::Status, ErrReport};
+use eyre::eyre;
+use std::convert::TryInto;
+use tcn::SignedReport;
+use warp::http::StatusCode;
+
+/// Fields of a signed report that the `tcn` crate does not expose, read from
+/// its serialization:
+///
+/// ```text
+/// [rvk: 32][tck_bytes: 32][j_1: u16 LE][j_2: u16 LE][memo_type: u8][memo_len: u8][memo][sig: 64]
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReportFields {
+    pub(crate) rvk: [u8; 32],
+    pub(crate) j_1: u16,
+    pub(crate) j_2: u16,
+    pub(crate) signature: [u8; 64],
+}
+
+impl ReportFields {
+    pub(crate) fn of(report: &SignedReport) -> Self {
+        let mut bytes = Vec::new();
+        report
+            .write(&mut bytes)
+            .expect("report serialization should be infallible");
+
+        let mut rvk = [0; 32];
+        rvk.copy_from_slice(&bytes[0..32]);
+        let mut signature = [0; 64];
+        signature.copy_from_slice(&bytes[bytes.len() - 64..]);
+        Self {
+            rvk,
+            j_1: u16::from_le_bytes([bytes[64], bytes[65]]),
+            j_2: u16::from_le_bytes([bytes[66], bytes[67]]),
+            signature,
+        }
+    }
+}
+
+/// Parse a batch of reports, each prefixed with its length as a `u16 LE`.
+pub(crate) fn read_batch(bytes: &[u8], max_reports: usize) -> Result<Vec<SignedReport>, ErrReport> {
+    let mut reports = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if reports.len() == max_reports {
+            return Err(eyre!("Batch has more than {} reports", max_reports))
+                .set_status(StatusCode::BAD_REQUEST);
+        }
+        let len = rest
+            .get(..2)
+            .map(|len| u16::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or(eyre!("Batch ends in the middle of a length prefix"))
+            .set_status(StatusCode::BAD_REQUEST)?;
+        let report = rest
+            .get(2..2 + len)
+            .ok_or(eyre!(
+                "Batch ends in the middle of report {}",
+                reports.len()
+            ))
+            .set_status(StatusCode::BAD_REQUEST)?;
+        let report = SignedReport::read(report)
+            .map_err(|e| ErrReport::from(e).wrap_err(format!("Invalid report {}", reports.len())))
+            .set_status(StatusCode::BAD_REQUEST)?;
+        reports.push(report);
+        rest = &rest[2 + len..];
+    }
+    Ok(reports)
+}
+
+#[test]
+fn test_report_fields() {
+    use tcn::{MemoType, ReportAuthorizationKey};
+
+    let report = ReportAuthorizationKey::new(rand::rngs::OsRng)
+        .create_report(MemoType::CoEpiV1, b"memo".to_vec(), 3, 40)
+        .unwrap();
+    let fields = ReportFields::of(&report);
+    assert_eq!((fields.j_1, fields.j_2), (3, 40));
+
+    let mut bytes = Vec::new();
+    report.write(&mut bytes).unwrap();
+    assert_eq!(&bytes[..32], &fields.rvk[..]);
+    assert_eq!(&bytes[bytes.len() - 64..], &fields.signature[..]);
+}
+
+#[test]
+fn test_read_batch() {
+    use tcn::{MemoType, ReportAuthorizationKey};
+
+    let mut bytes = Vec::new();
+    for j_1 in 1..4 {
+        let report = ReportAuthorizationKey::new(rand::rngs::OsRng)
+            .create_report(MemoType::CoEpiV1, Vec::new(), j_1, 10)
+            .unwrap();
+        let mut report_bytes = Vec::new();
+        report.write(&mut report_bytes).unwrap();
+        bytes.extend_from_slice(&(report_bytes.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&report_bytes);
+    }
+
+    let reports = read_batch(&bytes, 3).unwrap();
+    let starts: Vec<_> = reports.iter().map(|r| ReportFields::of(r).j_1).collect();
+    assert_eq!(starts, vec![1, 2, 3]);
+    assert!(read_batch(&bytes, 2).is_err());
+    assert!(read_batch(&bytes[..bytes.len() - 1], 3).is_err());
+    assert!(read_batch(&[], 3).unwrap().is_empty());
+}
@@ -0,0 +1,28 @@
+use serde::Serializer;
+
+/// Serialize bytes as a lowercase hex string.
+pub(crate) fn as_hex<S: Serializer>(
+    bytes: &impl AsRef<[u8]>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+/// Serialize optional bytes as a lowercase hex string, or none.
+pub(crate) fn option_as_hex<S: Serializer>(
+    bytes: &Option<impl AsRef<[u8]>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match bytes {
+        Some(bytes) => as_hex(bytes, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serialize a sequence of byte strings as lowercase hex strings.
+pub(crate) fn all_as_hex<S: Serializer>(
+    all: &[impl AsRef<[u8]>],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(all.iter().map(hex::encode))
+}
@@ -8,3 +8,8 @@ impl std::str::FromStr for Shard {
         Ok(Self(input.parse()?))
     }
 }
+
+/// Parse a comma-separated list of shard ids.
+pub fn parse_list(input: &str) -> Result<Vec<Shard>, std::num::ParseIntError> {
+    input.split(',').map(|shard| shard.trim().parse()).collect()
+}
@@ -1,19 +1,164 @@
 use super::{error::ErrReport, ReportTimestamp, Shard, SignedReport};
+use crate::auth::{code_id, generate_code, unix_now, AuthCodes, CodeId, CodeRecord};
 use crate::error::context::Status;
+use crate::keys::{Keyring, PublicKeyInfo};
+use crate::memo::{MemoPolicy, MemoValidator};
+use crate::merkle;
+use crate::receipt::Receipt;
+use crate::report::ReportFields;
+use bytes::Bytes;
 use eyre::eyre;
+use once_cell::unsync::OnceCell;
 use rand::rngs::OsRng;
 use rand::seq::SliceRandom;
-use std::collections::HashMap;
-use std::sync::Mutex;
-use tracing::{debug, info, instrument};
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::{task, time::delay_for};
+use tracing::{debug, info, instrument, warn};
 use warp::http::StatusCode;
 
+mod batch_cache;
+mod disk;
+mod encoding;
+mod export;
+mod filter;
+mod index;
+mod log;
+mod manifest;
+mod memory;
+mod tcns;
+mod wal;
+
+use batch_cache::BatchCache;
+pub(crate) use disk::DiskStore;
+pub(crate) use encoding::{Encoded, Encoding};
+pub(crate) use export::Export;
+use filter::BloomFilter;
+use index::{ReportIndex, ShardIndex};
+pub(crate) use log::{ConsistencyProof, InclusionProof, LogEntry, TreeHead};
+pub(crate) use manifest::{
+    encode_binary as encode_manifest, message as manifest_message, BatchInfo,
+};
+pub(crate) use memory::MemoryStore;
+use tcns::encode_sorted;
+
+/// A backend that stores batches of reports.
+///
+/// Implementations only handle persistence: checking report signatures and
+/// deciding which timeframe is current is done by [`Storage`].
+pub(crate) trait ReportStore: Send + Sync {
+    /// Append verified reports to the open batch for `timeframe`.
+    ///
+    /// Either every report is stored or, if an error is returned, none is.
+    fn save(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        reports: &[SignedReport],
+    ) -> Result<(), ErrReport>;
+
+    /// Seal every open batch whose timeframe is before `current`, returning
+    /// the batches that were sealed.
+    fn seal(&self, current: ReportTimestamp) -> Result<Vec<(Shard, ReportTimestamp)>, ErrReport>;
+
+    /// Delete every batch whose timeframe is before `oldest`.
+    fn expire(&self, oldest: ReportTimestamp) -> Result<(), ErrReport>;
+
+    /// Call `visit` with every stored report, open or sealed.
+    fn for_each_report(
+        &self,
+        visit: &mut dyn FnMut(Shard, ReportTimestamp, SignedReport),
+    ) -> Result<(), ErrReport>;
+
+    /// Persist the state of an authorization code, replacing any earlier
+    /// state that it [replaces](CodeRecord::replaces).
+    fn put_code(&self, id: CodeId, record: &CodeRecord) -> Result<(), ErrReport>;
+
+    /// Return every stored authorization code.
+    fn codes(&self) -> Result<Vec<(CodeId, CodeRecord)>, ErrReport>;
+
+    /// Append an entry to the transparency log of `shard`.
+    ///
+    /// Logs are never expired, so that they stay append-only.
+    fn append_log(&self, shard: Shard, entry: &LogEntry) -> Result<(), ErrReport>;
+
+    /// Return the transparency log of `shard`, oldest first.
+    fn log(&self, shard: Shard) -> Result<Vec<LogEntry>, ErrReport>;
+
+    /// Return every shard that holds batches.
+    fn shards(&self) -> Result<Vec<Shard>, ErrReport>;
+
+    /// Return the timeframes of the sealed batches of `shard`.
+    fn sealed(&self, shard: Shard) -> Result<Vec<ReportTimestamp>, ErrReport>;
+
+    /// Return the timeframes of the open batches of `shard`.
+    fn open(&self, shard: Shard) -> Result<Vec<ReportTimestamp>, ErrReport>;
+
+    /// Persist the compressed forms of the sealed batch for `timeframe`,
+    /// which are deleted along with it.
+    fn save_encoded(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        encoded: &Encoded,
+    ) -> Result<(), ErrReport>;
+
+    /// Return the compressed forms of the sealed batch for `timeframe`, if
+    /// they were saved.
+    fn encoded(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+    ) -> Result<Option<Encoded>, ErrReport>;
+
+    /// Persist the encoded Bloom filter of the sealed batch for `timeframe`,
+    /// which is deleted along with it.
+    fn save_filter(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        filter: &[u8],
+    ) -> Result<(), ErrReport>;
+
+    /// Return the encoded Bloom filter of the sealed batch for `timeframe`, if
+    /// it was saved.
+    fn filter(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<Option<Bytes>, ErrReport>;
+
+    /// Persist the sorted TCNs of the sealed batch for `timeframe`, which are
+    /// deleted along with it.
+    fn save_tcns(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        tcns: &[u8],
+    ) -> Result<(), ErrReport>;
+
+    /// Whether the sorted TCNs of the sealed batch for `timeframe` were saved,
+    /// without reading them.
+    fn has_tcns(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<bool, ErrReport>;
+
+    /// Return the sorted TCNs of the sealed batch for `timeframe`, if they
+    /// were saved.
+    fn tcns(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<Option<Bytes>, ErrReport>;
+
+    /// Return the sealed batch for `timeframe`.
+    ///
+    /// Open batches are never sealed on read; they are sealed by
+    /// [`Storage::seal_periodically`] once their timeframe has passed.
+    ///
+    /// The returned buffer shares the stored batch rather than copying it.
+    fn get(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<Bytes, ErrReport>;
+}
+
 pub(crate) enum StorageEntry {
     /// The storage entry is accepting new reports.
     Open(Vec<SignedReport>),
     /// The storage entry is finalized, and contains a serialization of
     /// all reports for the time interval in random order.
-    Sealed(Vec<u8>),
+    Sealed(Bytes),
 }
 
 impl Default for StorageEntry {
@@ -23,60 +168,973 @@ impl Default for StorageEntry {
 }
 
 impl StorageEntry {
-    /// Seal the entry, if it is open.
-    fn seal(&mut self) {
-        *self = match self {
-            StorageEntry::Sealed(_) => return,
-            StorageEntry::Open(ref mut reports) => {
-                let count = reports.len();
-                // Shuffle reports before serializing them.
-                reports.shuffle(&mut OsRng);
-                let mut bytes = Vec::<u8>::new();
-                for report in reports {
-                    report
-                        .write(&mut bytes)
-                        .expect("report serialization should be infallible");
-                }
-                info!(
-                    count,
-                    num_bytes = bytes.len(),
-                    "sealed reports into byte buffer"
-                );
-                StorageEntry::Sealed(bytes)
+    /// Seal the entry, if it is open, returning whether it was.
+    ///
+    /// `log` is called with the sealed bytes before the entry changes, and the
+    /// entry stays open if it fails.
+    fn seal(
+        &mut self,
+        log: impl FnOnce(&Bytes) -> Result<(), ErrReport>,
+    ) -> Result<bool, ErrReport> {
+        let bytes = match self {
+            StorageEntry::Sealed(_) => return Ok(false),
+            StorageEntry::Open(ref mut reports) => Bytes::from(serialize_shuffled(reports)),
+        };
+        log(&bytes)?;
+        *self = StorageEntry::Sealed(bytes);
+        Ok(true)
+    }
+}
+
+/// Shuffle reports and serialize them into a single byte buffer.
+fn serialize_shuffled(reports: &mut [SignedReport]) -> Vec<u8> {
+    let count = reports.len();
+    // Shuffle reports before serializing them.
+    reports.shuffle(&mut OsRng);
+    let mut bytes = Vec::<u8>::new();
+    for report in reports {
+        report
+            .write(&mut bytes)
+            .expect("report serialization should be infallible");
+    }
+    info!(
+        count,
+        num_bytes = bytes.len(),
+        "sealed reports into byte buffer"
+    );
+    bytes
+}
+
+/// Parse a sequence of serialized reports, returning them along with the
+/// length of the intact prefix of `bytes`.
+///
+/// A crash in the middle of an append can leave a partial report at the end
+/// of an open segment; it is dropped, since it was never acknowledged.
+fn read_reports(bytes: &[u8]) -> (Vec<SignedReport>, usize) {
+    let mut reader = Cursor::new(bytes);
+    let mut reports = Vec::new();
+    let mut valid_len = 0;
+    while valid_len < bytes.len() {
+        match SignedReport::read(&mut reader) {
+            Ok(report) => reports.push(report),
+            Err(error) => {
+                warn!(%error, "ignoring truncated report at end of segment");
+                break;
+            }
+        }
+        valid_len = reader.position() as usize;
+    }
+    (reports, valid_len)
+}
+
+/// Return every TCN generated by the reports in a sealed batch.
+fn expand_tcns(batch: &[u8]) -> Vec<[u8; 16]> {
+    read_reports(batch)
+        .0
+        .into_iter()
+        .filter_map(|report| report.verify().ok())
+        .flat_map(|report| {
+            report
+                .temporary_contact_numbers()
+                .map(|tcn| tcn.0)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Encode the Bloom filter of a sealed batch's TCNs.
+fn encode_filter(tcns: &[[u8; 16]]) -> Bytes {
+    Bytes::from(BloomFilter::of(tcns).encode())
+}
+
+/// Check that a report's TCK range is well-formed and within the server's
+/// limit, since every client that downloads the report must ratchet through
+/// the whole range.
+fn check_tck_range(fields: &ReportFields) -> Result<(), ErrReport> {
+    if fields.j_1 == 0 {
+        return Err(eyre!("Report has j_1 = 0, but TCK indices start at 1"))
+            .set_status(StatusCode::BAD_REQUEST);
+    }
+    if fields.j_2 < fields.j_1 {
+        return Err(eyre!(
+            "Report has j_2 = {} before j_1 = {}",
+            fields.j_2,
+            fields.j_1
+        ))
+        .set_status(StatusCode::BAD_REQUEST);
+    }
+    let tcks = u32::from(fields.j_2 - fields.j_1) + 1;
+    let max = crate::OPTIONS.max_tcks_per_report();
+    if tcks > max {
+        return Err(eyre!(
+            "Report covers {} TCKs (j_1 = {}, j_2 = {}), but at most {} are allowed",
+            tcks,
+            fields.j_1,
+            fields.j_2,
+            max
+        ))
+        .set_status(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
+}
+
+/// A sealed batch, along with its description and compressed forms.
+pub(crate) struct SealedBatch {
+    pub(crate) bytes: Bytes,
+    pub(crate) info: BatchInfo,
+    pub(crate) encoded: Arc<Encoded>,
+}
+
+/// The outcome of saving a single report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Saved {
+    /// The report was stored, with a receipt for the batch it was accepted
+    /// into.
+    Stored(Receipt),
+    /// The shard already held the report, so it was not stored again.  The
+    /// receipt is for the batch it was first accepted into.
+    Duplicate(Receipt),
+    /// The report is valid, but was not stored because another report in
+    /// the same batch was rejected.
+    Withheld,
+}
+
+/// Mark the reports of a batch that has been rejected as withheld.
+fn withhold(
+    results: impl IntoIterator<Item = Result<Saved, ErrReport>>,
+) -> Vec<Result<Saved, ErrReport>> {
+    results
+        .into_iter()
+        .map(|result| result.map(|_| Saved::Withheld))
+        .collect()
+}
+
+/// Reject requests for the batch of the current timeframe, which is still
+/// accepting reports, or for a batch that has expired.
+fn check_published(timeframe: ReportTimestamp) -> Result<(), ErrReport> {
+    let current = ReportTimestamp::now()?;
+    if timeframe == current {
+        return Err(eyre!("Cannot request entries for current timeframe"))
+            .set_status(StatusCode::FORBIDDEN)?;
+    }
+    if timeframe < oldest_retained()? {
+        return Err(eyre!("Report batch has expired")).set_status(StatusCode::GONE)?;
+    }
+    Ok(())
+}
+
+/// List the retained sealed batches of `shard`, oldest first.
+fn retained_sealed(
+    store: &dyn ReportStore,
+    shard: Shard,
+) -> Result<Vec<ReportTimestamp>, ErrReport> {
+    let oldest = oldest_retained()?;
+    let mut timeframes = store.sealed(shard)?;
+    timeframes.retain(|timeframe| *timeframe >= oldest);
+    timeframes.sort();
+    Ok(timeframes)
+}
+
+/// Treat a batch that expired since it was listed as missing.
+/// Save `reports` to the open batch of `shard` for `now`, and return the
+/// timeframe they were saved to.
+///
+/// The batch may be sealed between reading the clock and saving to it, when a
+/// submission arrives just as its timeframe ends, so if the clock has moved on
+/// by the time the save fails, the reports are saved to the next batch instead.
+fn save_current(
+    store: &dyn ReportStore,
+    shard: Shard,
+    now: ReportTimestamp,
+    reports: &[SignedReport],
+) -> Result<ReportTimestamp, ErrReport> {
+    match store.save(shard, now, reports) {
+        Ok(()) => Ok(now),
+        Err(error) => {
+            let next = ReportTimestamp::now()?;
+            if next <= now {
+                return Err(error);
             }
+            debug!(?now, ?next, "batch sealed while saving, retrying");
+            store.save(shard, next, reports)?;
+            Ok(next)
         }
     }
 }
 
-#[derive(Default)]
+fn skip_expired<T>(result: Result<T, ErrReport>) -> Result<Option<T>, ErrReport> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.0.context().status == StatusCode::NOT_FOUND => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The oldest timeframe whose batches are still retained.
+fn oldest_retained() -> Result<ReportTimestamp, ErrReport> {
+    let retention = Duration::from_secs(86400 * crate::OPTIONS.retention_days);
+    let cutoff = SystemTime::now()
+        .checked_sub(retention)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    Ok(ReportTimestamp::from_time(cutoff)?)
+}
+
 pub struct Storage {
-    map: Mutex<HashMap<Shard, HashMap<ReportTimestamp, StorageEntry>>>,
+    store: Arc<dyn ReportStore>,
+    index: ReportIndex,
+    /// The signed descriptions of sealed batches.
+    manifest: BatchCache<BatchInfo>,
+    /// The compressed forms of sealed batches.
+    encoded: BatchCache<Arc<Encoded>>,
+    /// The encoded Bloom filters of sealed batches' TCNs.
+    filters: BatchCache<Bytes>,
+    /// Whether the sorted TCNs of sealed batches are saved and served.
+    expanded: bool,
+    /// The sealed batches that [`Storage::prepare`] has finished with.
+    prepared: BatchCache<()>,
+    memo: Box<dyn MemoValidator>,
+    codes: AuthCodes,
+    require_auth_code: bool,
+    export: Option<Export>,
+    keys: Arc<Keyring>,
+    /// The transparency logs that have been loaded from the store.
+    logs: Mutex<HashMap<Shard, Vec<LogEntry>>>,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            store: Arc::new(MemoryStore::default()),
+            index: ReportIndex::default(),
+            manifest: BatchCache::default(),
+            encoded: BatchCache::default(),
+            filters: BatchCache::default(),
+            expanded: false,
+            prepared: BatchCache::default(),
+            memo: Box::new(MemoPolicy::default()),
+            codes: AuthCodes::default(),
+            require_auth_code: false,
+            export: None,
+            keys: Arc::default(),
+            logs: Mutex::default(),
+        }
+    }
 }
 
 impl Storage {
+    /// Wrap `store`, indexing the reports and authorization codes it already
+    /// holds.
+    ///
+    /// A code left pending by a submission that was interrupted is consumed
+    /// if the submission's reports were stored, and issued again otherwise.
+    pub(crate) fn new(store: impl ReportStore + 'static) -> Result<Self, ErrReport> {
+        let index = ReportIndex::default();
+        store.for_each_report(&mut |shard, timeframe, report| {
+            index
+                .shard(shard)
+                .lock()
+                .unwrap()
+                .insert(&report, timeframe);
+        })?;
+        let mut records = store.codes()?;
+        for (id, record) in records.iter_mut().filter(|(_, record)| record.pending) {
+            let stored = record
+                .consumed_by
+                .iter()
+                .flatten()
+                .all(|signature| index.contains(signature));
+            if !stored {
+                record.consumed_by = None;
+            }
+            record.pending = false;
+            debug!(id = %hex::encode(*id), stored, "resolved pending authorization code");
+            store.put_code(*id, record)?;
+        }
+        let codes = AuthCodes::from_records(records);
+        Ok(Self {
+            store: Arc::new(store),
+            index,
+            manifest: BatchCache::default(),
+            encoded: BatchCache::default(),
+            filters: BatchCache::default(),
+            expanded: false,
+            prepared: BatchCache::default(),
+            memo: Box::new(MemoPolicy::default()),
+            codes,
+            require_auth_code: false,
+            export: None,
+            keys: Arc::default(),
+            logs: Mutex::default(),
+        })
+    }
+
+    /// Check the memo of every submitted report with `validator`.
+    pub(crate) fn with_memo_validator(self, validator: impl MemoValidator + 'static) -> Self {
+        Self {
+            memo: Box::new(validator),
+            ..self
+        }
+    }
+
+    /// Require every submission to consume an issued authorization code.
+    pub(crate) fn require_auth_code(self) -> Self {
+        Self {
+            require_auth_code: true,
+            ..self
+        }
+    }
+
+    /// Publish sealed batches to `export` each time batches are sealed.
+    pub(crate) fn with_export(self, export: Export) -> Self {
+        Self {
+            export: Some(export),
+            ..self
+        }
+    }
+
+    /// Expand the reports of each sealed batch into its sorted TCNs, save them
+    /// to the store, and serve them from [`Storage::tcns`].
+    pub(crate) fn with_expanded_tcns(self) -> Self {
+        Self {
+            expanded: true,
+            ..self
+        }
+    }
+
+    /// Sign with the keys in `keys`.
+    pub(crate) fn with_keys(self, keys: Keyring) -> Self {
+        Self {
+            keys: Arc::new(keys),
+            ..self
+        }
+    }
+
+    /// The public halves of the server's signing keys.
+    pub(crate) fn public_keys(&self) -> Vec<PublicKeyInfo> {
+        self.keys.public_keys()
+    }
+
+    /// Sign `message` with the current signing key, if there is one.
+    pub(crate) fn sign(&self, message: &[u8]) -> Option<(PublicKeyInfo, [u8; 64])> {
+        self.keys.sign(message)
+    }
+
+    /// Issue `count` new authorization codes, returning each code and its id.
+    pub(crate) fn issue_codes(
+        &self,
+        count: usize,
+        expires_at: Option<u64>,
+    ) -> Result<Vec<(String, CodeId)>, ErrReport> {
+        let mut issued = Vec::with_capacity(count);
+        while issued.len() < count {
+            let code = generate_code();
+            let id = code_id(&code);
+            if let Some(record) = self.codes.issue(id, expires_at) {
+                if let Err(e) = self.store.put_code(id, &record) {
+                    let _ = self.codes.revoke(&id);
+                    return Err(e);
+                }
+                issued.push((code, id));
+            }
+        }
+        info!(count, ?expires_at, "issued authorization codes");
+        Ok(issued)
+    }
+
+    /// Accept codes issued elsewhere, without expiry, skipping known codes.
+    pub(crate) fn import_codes<'a>(
+        &self,
+        codes: impl IntoIterator<Item = &'a str>,
+    ) -> Result<usize, ErrReport> {
+        let mut imported = 0;
+        for code in codes {
+            let id = code_id(code);
+            if let Some(record) = self.codes.issue(id, None) {
+                self.store.put_code(id, &record)?;
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// List the authorization codes that can still be used, with their
+    /// expiry times.
+    pub(crate) fn outstanding_codes(&self) -> Vec<(CodeId, Option<u64>)> {
+        self.codes.outstanding()
+    }
+
+    /// Stop accepting an authorization code, for good.
+    pub(crate) fn revoke_code(&self, id: CodeId) -> Result<(), ErrReport> {
+        let record = self.codes.revoke(&id)?;
+        self.store.put_code(id, &record)
+    }
+
+    /// Seal the previous batches of every shard each time a new timeframe
+    /// begins, and delete batches that have outlived the retention window.
+    ///
+    /// Batches left open by an earlier run are sealed immediately.
+    pub(crate) async fn seal_periodically(&self) {
+        loop {
+            let current = match ReportTimestamp::now() {
+                Ok(current) => current,
+                Err(error) => {
+                    warn!(%error, "could not determine current timeframe");
+                    delay_for(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            if let Err(error) = task::block_in_place(|| self.store.seal(current)) {
+                warn!(?error, "failed to seal report batches");
+            }
+            if let Err(error) = task::block_in_place(|| self.extend_logs()) {
+                warn!(?error, "failed to log sealed batches");
+            }
+            match oldest_retained() {
+                Ok(oldest) => {
+                    if let Err(error) = task::block_in_place(|| self.store.expire(oldest)) {
+                        warn!(?error, "failed to expire report batches");
+                    }
+                    self.expire_caches(oldest);
+                }
+                Err(error) => warn!(?error, "could not determine retention window"),
+            }
+            if let Some(ref export) = self.export {
+                if let Err(error) = task::block_in_place(|| self.export(export)) {
+                    warn!(?error, "failed to export report batches");
+                }
+            }
+            // Preparing batches sealed by an earlier run can take a while, so
+            // it comes last, to not hold up the steps above.
+            if let Err(error) = task::block_in_place(|| self.prepare()) {
+                warn!(?error, "failed to prepare sealed batches");
+            }
+
+            let next = ReportTimestamp(current.0 + 1).start_time();
+            delay_for(next.duration_since(SystemTime::now()).unwrap_or_default()).await;
+        }
+    }
+
+    /// Forget everything remembered about batches before `oldest`.
+    fn expire_caches(&self, oldest: ReportTimestamp) {
+        self.index.expire(oldest);
+        self.manifest.expire(oldest);
+        self.encoded.expire(oldest);
+        self.filters.expire(oldest);
+        self.prepared.expire(oldest);
+    }
+
+    /// Publish the retained sealed batches of every shard to `export`.
+    ///
+    /// A shard that fails to publish is skipped until the next export, so
+    /// that it does not hold back the others.
+    fn export(&self, export: &Export) -> Result<(), ErrReport> {
+        for shard in self.store.shards()? {
+            let published = self.sealed_batches(shard).and_then(|batches| {
+                export.publish(shard, &batches, |timeframe| {
+                    self.store.get(shard, timeframe)
+                })
+            });
+            if let Err(error) = published {
+                warn!(?shard, ?error, "failed to export shard");
+            }
+        }
+        Ok(())
+    }
+
+    /// Commit every sealed batch that is not yet in its shard's transparency
+    /// log to the log.
+    ///
+    /// Any sealed batch missing from the log is committed, so a batch that
+    /// was sealed but not logged before a crash is logged on the next run,
+    /// and a batch that failed to seal is logged once it is sealed, even if
+    /// later batches were logged in the meantime.
+    fn extend_logs(&self) -> Result<(), ErrReport> {
+        let mut logs = self.logs.lock().unwrap();
+        for shard in self.store.shards()? {
+            let log = Self::loaded_log(&mut logs, &*self.store, shard)?;
+            let logged: HashSet<_> = log.iter().map(|entry| entry.timestamp).collect();
+            let mut sealed = self.store.sealed(shard)?;
+            sealed.retain(|timeframe| !logged.contains(&timeframe.0));
+            sealed.sort();
+            for timeframe in sealed {
+                let entry = LogEntry::of(timeframe, &self.store.get(shard, timeframe)?);
+                self.store.append_log(shard, &entry)?;
+                debug!(?shard, ?timeframe, "logged sealed batch");
+                log.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the transparency log of `shard`, loading it if necessary.
+    fn loaded_log<'a>(
+        logs: &'a mut HashMap<Shard, Vec<LogEntry>>,
+        store: &dyn ReportStore,
+        shard: Shard,
+    ) -> Result<&'a mut Vec<LogEntry>, ErrReport> {
+        Ok(match logs.entry(shard) {
+            Entry::Occupied(log) => log.into_mut(),
+            Entry::Vacant(log) => log.insert(store.log(shard)?),
+        })
+    }
+
+    /// Return the entries of the transparency log of `shard`, oldest first.
+    #[instrument(skip(self))]
+    pub(crate) async fn log(&self, shard: Shard) -> Result<Vec<LogEntry>, ErrReport> {
+        debug!("got request for log entries");
+        let mut logs = self.logs.lock().unwrap();
+        Ok(Self::loaded_log(&mut logs, &*self.store, shard)?.clone())
+    }
+
+    /// Return the current tree head of the transparency log of `shard`,
+    /// signed if the server has a signing key.
     #[instrument(skip(self))]
+    pub(crate) async fn tree_head(&self, shard: Shard) -> Result<TreeHead, ErrReport> {
+        debug!("got request for tree head");
+        let leaves = self.log_leaves(shard)?;
+        let head = TreeHead {
+            shard: shard.0,
+            tree_size: leaves.len() as u64,
+            root_hash: merkle::root(&leaves),
+            timestamp: unix_now(),
+            public_key: None,
+            signature: None,
+        };
+        Ok(head.sign(|message| self.keys.sign(message)))
+    }
+
+    /// Prove that the first `first` entries of the transparency log of
+    /// `shard` are a prefix of its first `second` entries.
+    #[instrument(skip(self))]
+    pub(crate) async fn consistency_proof(
+        &self,
+        shard: Shard,
+        first: u64,
+        second: u64,
+    ) -> Result<ConsistencyProof, ErrReport> {
+        debug!("got request for consistency proof");
+        let leaves = self.log_leaves(shard)?;
+        if first == 0 || first > second || second > leaves.len() as u64 {
+            return Err(eyre!(
+                "Tree sizes must satisfy 0 < {} <= {} <= {}",
+                first,
+                second,
+                leaves.len()
+            ))
+            .set_status(StatusCode::BAD_REQUEST);
+        }
+        let proof = merkle::consistency_proof(first as usize, &leaves[..second as usize]);
+        Ok(ConsistencyProof {
+            first,
+            second,
+            proof,
+        })
+    }
+
+    /// Prove that the report with the given SHA-256 hash is in the batch for
+    /// `timeframe`, and that the batch is in the current transparency log of
+    /// `shard`.
+    ///
+    /// Only reports in retained batches can be proven, but the batches
+    /// themselves stay in the log after they expire.
+    #[instrument(skip(self))]
+    pub(crate) async fn inclusion_proof(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        report_sha256: merkle::Hash,
+    ) -> Result<InclusionProof, ErrReport> {
+        debug!("got request for inclusion proof");
+        let entries = self.log(shard).await?;
+        let entry_index = entries
+            .iter()
+            .position(|entry| entry.timestamp == timeframe.0)
+            .ok_or(eyre!("Report batch is not in the log"))
+            .set_status(StatusCode::NOT_FOUND)?;
+        // Proving a report means hashing every report in its batch, so it is
+        // done off the executor along with reading the batch.
+        self.blocking(move |store| {
+            let batch = store.get(shard, timeframe)?;
+            let (report_index, report_proof) = log::prove_report(&batch, &report_sha256)
+                .ok_or(eyre!("Report is not in this batch"))
+                .set_status(StatusCode::NOT_FOUND)?;
+            let leaves: Vec<_> = entries.iter().map(LogEntry::leaf_hash).collect();
+            Ok(InclusionProof {
+                tree_size: leaves.len() as u64,
+                entry: entries[entry_index],
+                entry_index: entry_index as u64,
+                entry_proof: merkle::inclusion_proof(entry_index, &leaves),
+                report_index: report_index as u64,
+                report_proof,
+            })
+        })
+        .await
+    }
+
+    fn log_leaves(&self, shard: Shard) -> Result<Vec<merkle::Hash>, ErrReport> {
+        let mut logs = self.logs.lock().unwrap();
+        let log = Self::loaded_log(&mut logs, &*self.store, shard)?;
+        Ok(log.iter().map(LogEntry::leaf_hash).collect())
+    }
+
+    /// Describe and sign, compress, filter and, if enabled, expand every
+    /// sealed batch that has not been yet, so that requests for them do not
+    /// have to.
+    ///
+    /// This covers batches sealed by an earlier run as well as new ones.
+    /// Compressed forms, filters and expanded TCNs are saved to the store, so
+    /// that they are only computed once.
+    fn prepare(&self) -> Result<(), ErrReport> {
+        let oldest = oldest_retained()?;
+        for shard in self.store.shards()? {
+            for timeframe in self.store.sealed(shard)? {
+                if timeframe < oldest || self.prepared.get(shard, timeframe).is_some() {
+                    continue;
+                }
+                let batch = || self.store.get(shard, timeframe);
+                // Expanding a batch is the costliest step, so its TCNs are
+                // computed at most once for the filter and the expanded form.
+                let batch_tcns = OnceCell::new();
+                let tcns = || {
+                    batch_tcns
+                        .get_or_try_init(|| batch().map(|batch| expand_tcns(&batch)))
+                        .cloned()
+                };
+                let encode = || match self.store.encoded(shard, timeframe)? {
+                    Some(encoded) => Ok(Some(Arc::new(encoded))),
+                    None => {
+                        let encoded = Encoded::of(&batch()?);
+                        self.store.save_encoded(shard, timeframe, &encoded)?;
+                        Ok(Some(Arc::new(encoded)))
+                    }
+                };
+                let filter = || match self.store.filter(shard, timeframe)? {
+                    Some(filter) => Ok(Some(filter)),
+                    None => {
+                        let filter = encode_filter(&tcns()?);
+                        self.store.save_filter(shard, timeframe, &filter)?;
+                        Ok(Some(filter))
+                    }
+                };
+                let expand = || {
+                    if self.expanded && !self.store.has_tcns(shard, timeframe)? {
+                        let expanded = encode_sorted(tcns()?);
+                        self.store.save_tcns(shard, timeframe, &expanded)?;
+                    }
+                    Ok(())
+                };
+                match self
+                    .describe(shard, timeframe, batch)
+                    .and_then(|_| self.encoded.get_or_load(shard, timeframe, encode))
+                    .and_then(|_| self.filters.get_or_load(shard, timeframe, filter))
+                    .and_then(|_| expand())
+                {
+                    Ok(()) => self.prepared.insert(shard, timeframe, ()),
+                    Err(error) => {
+                        warn!(?error, ?shard, ?timeframe, "failed to prepare sealed batch")
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the signed description of a sealed batch, computing it from
+    /// `batch` if necessary.
+    fn describe(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        batch: impl FnOnce() -> Result<Bytes, ErrReport>,
+    ) -> Result<BatchInfo, ErrReport> {
+        self.manifest.get_or_insert_with(shard, timeframe, || {
+            let info = BatchInfo::of(timeframe, &batch()?);
+            Ok(info.sign(shard, |message| self.keys.sign(message)))
+        })
+    }
+
+    /// Return the signed description of a sealed batch, computing it off the
+    /// executor if necessary.
+    async fn description(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+    ) -> Result<BatchInfo, ErrReport> {
+        let keys = self.keys.clone();
+        let info = self
+            .cached(&self.manifest, shard, timeframe, move |store| {
+                let info = BatchInfo::of(timeframe, &store.get(shard, timeframe)?);
+                Ok(Some(info.sign(shard, |message| keys.sign(message))))
+            })
+            .await?;
+        Ok(info.expect("descriptions are always computed"))
+    }
+
+    /// Return the value of `cache` for a sealed batch, loading it with `load`
+    /// off the executor if necessary.
+    async fn cached<T: Clone + Send + 'static>(
+        &self,
+        cache: &BatchCache<T>,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        load: impl FnOnce(&dyn ReportStore) -> Result<Option<T>, ErrReport> + Send + 'static,
+    ) -> Result<Option<T>, ErrReport> {
+        if let Some(value) = cache.get(shard, timeframe) {
+            return Ok(Some(value));
+        }
+        let value = self.blocking(load).await?;
+        cache.get_or_load(shard, timeframe, || Ok(value))
+    }
+
+    /// Save a report to `shard`, returning a receipt for the batch it was
+    /// accepted into.
+    ///
+    /// A report that was already saved gets a receipt for the batch it was
+    /// first accepted into.
+    #[instrument(skip(self, authorization))]
     pub(crate) async fn save(
         &self,
         shard: Shard,
         report: SignedReport,
-    ) -> Result<String, ErrReport> {
+        authorization: Option<&str>,
+    ) -> Result<Receipt, ErrReport> {
         debug!("got report");
-        let now = ReportTimestamp::now()?;
-        let mut map = self.map.lock().unwrap();
-        match map.entry(shard).or_default().entry(now).or_default() {
-            StorageEntry::Open(ref mut reports) => {
-                report
-                    .clone()
-                    .verify()
-                    .set_status(StatusCode::BAD_REQUEST)?;
-                reports.push(report);
-                Ok(format!("report saved"))
+        let mut results = self.save_batch(shard, vec![report], authorization).await?;
+        match results.remove(0)? {
+            Saved::Stored(receipt) | Saved::Duplicate(receipt) => Ok(receipt),
+            Saved::Withheld => unreachable!("a single report is only withheld if it is rejected"),
+        }
+    }
+
+    /// Save several reports to `shard` at once, returning the outcome for
+    /// each of them, with a receipt for each report that was accepted.
+    ///
+    /// The batch is atomic: if any report is rejected, none of the others are
+    /// stored either.  A single authorization code covers the whole batch.
+    #[instrument(skip(self, reports, authorization))]
+    pub(crate) async fn save_batch(
+        &self,
+        shard: Shard,
+        reports: Vec<SignedReport>,
+        authorization: Option<&str>,
+    ) -> Result<Vec<Result<Saved, ErrReport>>, ErrReport> {
+        debug!(count = reports.len(), "got batch of reports");
+        let fields: Vec<_> = reports.iter().map(|r| self.validate(r)).collect();
+        if fields.iter().any(Result::is_err) {
+            return Ok(withhold(
+                fields.into_iter().map(|f| f.map(|_| Saved::Withheld)),
+            ));
+        }
+        let signatures: Vec<_> = fields
+            .into_iter()
+            .filter_map(Result::ok)
+            .map(|f| f.signature)
+            .collect();
+        let reservation = if self.require_auth_code {
+            Some(self.codes.reserve(authorization, &signatures)?)
+        } else {
+            None
+        };
+
+        // Hold the shard's index lock until the reports are stored, so that
+        // concurrent resubmissions cannot both be saved.  Reports earlier in
+        // the batch are checked against a separate index, which is only merged
+        // once the batch is stored.  A consumed code is recorded as pending
+        // before the reports are stored, in case storing them is interrupted.
+        let index = self.index.shard(shard);
+        let pending_code = reservation.as_ref().and_then(|r| r.pending(&signatures));
+        let received_at = unix_now();
+        let (results, stored) = self
+            .blocking(move |store| {
+                let mut index = index.lock().unwrap();
+                let now = ReportTimestamp::now()?;
+                let mut pending = ShardIndex::default();
+                let mut results: Vec<_> = reports
+                    .iter()
+                    .map(|report| {
+                        if let Some(timeframe) =
+                            index.lookup(report).or_else(|| pending.lookup(report))
+                        {
+                            debug!(?timeframe, "ignoring duplicate report");
+                            let receipt = Receipt::new(report, shard, timeframe, received_at);
+                            return Ok(Saved::Duplicate(receipt));
+                        }
+                        index.check_overlap(report)?;
+                        pending.check_overlap(report)?;
+                        pending.insert(report, now);
+                        Ok(Saved::Stored(Receipt::new(report, shard, now, received_at)))
+                    })
+                    .collect();
+                if results.iter().any(Result::is_err) {
+                    return Ok((withhold(results), false));
+                }
+
+                let new: Vec<_> = reports
+                    .into_iter()
+                    .zip(&results)
+                    .filter(|(_, result)| matches!(result, Ok(Saved::Stored(_))))
+                    .map(|(report, _)| report)
+                    .collect();
+                if new.is_empty() {
+                    return Ok((results, false));
+                }
+                if let Some((id, record)) = pending_code {
+                    store.put_code(id, &record)?;
+                }
+                let timeframe = save_current(store, shard, now, &new)?;
+                for report in &new {
+                    index.insert(report, timeframe);
+                }
+                for result in &mut results {
+                    if let Ok(Saved::Stored(receipt)) = result {
+                        receipt.timestamp = timeframe;
+                    }
+                }
+                Ok((results, true))
+            })
+            .await?;
+        if stored {
+            if let Some((id, record)) = reservation.and_then(|r| r.commit(signatures)) {
+                self.consume_code(id, record).await;
             }
-            StorageEntry::Sealed(_) => {
-                Err(eyre!("Current entry is already sealed. Is time broken?"))
-                    .set_status(StatusCode::CONFLICT)?
+        }
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                result.map(|saved| match saved {
+                    Saved::Stored(receipt) => Saved::Stored(self.sign_receipt(receipt)),
+                    Saved::Duplicate(receipt) => Saved::Duplicate(self.sign_receipt(receipt)),
+                    Saved::Withheld => Saved::Withheld,
+                })
+            })
+            .collect())
+    }
+
+    /// Save `report` to each of `shards`, verifying it only once, and return
+    /// a receipt for each shard.
+    ///
+    /// A shard that already holds the report is skipped, and its receipt is
+    /// for the batch the report was first accepted into, but if the report
+    /// overlaps reports published in any of the shards, it is saved to none
+    /// of them.  A single authorization code covers every shard.
+    #[instrument(skip(self, report, authorization))]
+    pub(crate) async fn save_to_shards(
+        &self,
+        shards: &[Shard],
+        report: SignedReport,
+        authorization: Option<&str>,
+    ) -> Result<Vec<Receipt>, ErrReport> {
+        debug!("got report for several shards");
+        let fields = self.validate(&report)?;
+        let reservation = if self.require_auth_code {
+            Some(self.codes.reserve(authorization, &[fields.signature])?)
+        } else {
+            None
+        };
+
+        // Lock the shards' indices in a fixed order, so that concurrent
+        // multi-shard submissions cannot deadlock.
+        let mut shards = shards.to_vec();
+        shards.sort_by_key(|shard| shard.0);
+        shards.dedup();
+        let indices: Vec<_> = shards
+            .iter()
+            .map(|shard| self.index.shard(*shard))
+            .collect();
+        let pending_code = reservation
+            .as_ref()
+            .and_then(|r| r.pending(&[fields.signature]));
+        let received_at = unix_now();
+        let (receipts, stored) = self
+            .blocking(move |store| {
+                let mut indices: Vec<_> =
+                    indices.iter().map(|index| index.lock().unwrap()).collect();
+                let now = ReportTimestamp::now()?;
+
+                let mut receipts = Vec::with_capacity(shards.len());
+                let mut new = Vec::new();
+                for (shard, index) in shards.iter().zip(indices.iter_mut()) {
+                    if let Some(timeframe) = index.lookup(&report) {
+                        debug!(?shard, ?timeframe, "ignoring duplicate report");
+                        receipts.push(Receipt::new(&report, *shard, timeframe, received_at));
+                        continue;
+                    }
+                    index
+                        .check_overlap(&report)
+                        .map_err(|e| e.wrap_err(format!("Report overlaps in shard {}", shard.0)))?;
+                    new.push((*shard, index, receipts.len()));
+                    receipts.push(Receipt::new(&report, *shard, now, received_at));
+                }
+                if new.is_empty() {
+                    return Ok((receipts, false));
+                }
+                if let Some((id, record)) = pending_code {
+                    store.put_code(id, &record)?;
+                }
+
+                // Each shard's batch is stored separately, so a failure part
+                // way through leaves the report in some of the shards; a retry
+                // treats those as duplicates and stores the rest.
+                for (shard, index, receipt) in new {
+                    let timeframe = save_current(store, shard, now, std::slice::from_ref(&report))?;
+                    index.insert(&report, timeframe);
+                    receipts[receipt].timestamp = timeframe;
+                }
+                Ok((receipts, true))
+            })
+            .await?;
+        if stored {
+            if let Some((id, record)) = reservation.and_then(|r| r.commit(vec![fields.signature])) {
+                self.consume_code(id, record).await;
             }
         }
+        Ok(receipts
+            .into_iter()
+            .map(|receipt| self.sign_receipt(receipt))
+            .collect())
+    }
+
+    /// Sign `receipt` with the current signing key, if there is one.
+    fn sign_receipt(&self, receipt: Receipt) -> Receipt {
+        receipt.sign(|message| self.keys.sign(message))
+    }
+
+    /// Persist that a code was consumed by a stored submission.
+    ///
+    /// The reports are already stored, so a failure is only logged: the code
+    /// stays pending in the store, and is resolved as consumed on restart.
+    async fn consume_code(&self, id: CodeId, record: CodeRecord) {
+        if let Err(error) = self
+            .blocking(move |store| store.put_code(id, &record))
+            .await
+        {
+            warn!(?error, "failed to persist consumed authorization code");
+        }
+    }
+
+    /// Run `f` on a thread where blocking is allowed, since it may read from
+    /// disk or wait for index locks held while other reports are written to
+    /// the store.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn ReportStore) -> Result<T, ErrReport> + Send + 'static,
+    ) -> Result<T, ErrReport> {
+        let store = self.store.clone();
+        task::spawn_blocking(move || f(&*store)).await?
+    }
+
+    /// Check the parts of a report that do not depend on what is stored.
+    fn validate(&self, report: &SignedReport) -> Result<ReportFields, ErrReport> {
+        let verified = report
+            .clone()
+            .verify()
+            .set_status(StatusCode::BAD_REQUEST)?;
+        self.memo
+            .validate(verified.memo_type(), verified.memo_data())?;
+        let fields = ReportFields::of(report);
+        check_tck_range(&fields)?;
+        Ok(fields)
     }
 
     #[instrument(skip(self))]
@@ -84,33 +1142,823 @@ impl Storage {
         &self,
         shard: Shard,
         timeframe: ReportTimestamp,
-    ) -> Result<Vec<u8>, ErrReport> {
+    ) -> Result<SealedBatch, ErrReport> {
         debug!(?timeframe, "got request for entries");
-        // Reject requests for the current timeframe.
-        let current = ReportTimestamp::now()?;
-        if timeframe == current {
-            return Err(eyre!("Cannot request entries for current timeframe"))
-                .set_status(StatusCode::FORBIDDEN)?;
-        }
-
-        let mut map = self.map.lock().unwrap();
-        let entry = map
-            .get_mut(&shard)
-            .ok_or(eyre!("No entries for this shard"))
-            .set_status(StatusCode::NOT_FOUND)?
-            .get_mut(&timeframe)
-            .ok_or(eyre!("No entries for this timeframe"))
-            .set_status(StatusCode::NOT_FOUND)?;
+        check_published(timeframe)?;
+        let bytes = self
+            .blocking(move |store| store.get(shard, timeframe))
+            .await?;
+        let info = self.description(shard, timeframe).await?;
+        // Batches are only compressed once they are sealed, and until then are
+        // served as they are.
+        let encoded = self
+            .cached(&self.encoded, shard, timeframe, move |store| {
+                Ok(store.encoded(shard, timeframe)?.map(Arc::new))
+            })
+            .await?
+            .unwrap_or_default();
+        Ok(SealedBatch {
+            bytes,
+            info,
+            encoded,
+        })
+    }
+
+    /// Return the encoded Bloom filter of the TCNs generated by the reports in
+    /// a sealed batch.
+    ///
+    /// Filters are computed when batches are prepared, so one is only
+    /// computed here, off the executor, if its batch has not been prepared yet.
+    #[instrument(skip(self))]
+    pub(crate) async fn filter(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+    ) -> Result<Bytes, ErrReport> {
+        debug!(?timeframe, "got request for filter");
+        check_published(timeframe)?;
+        let filter = self
+            .cached(&self.filters, shard, timeframe, move |store| {
+                if let Some(filter) = store.filter(shard, timeframe)? {
+                    return Ok(Some(filter));
+                }
+                let filter = encode_filter(&expand_tcns(&store.get(shard, timeframe)?));
+                store.save_filter(shard, timeframe, &filter)?;
+                Ok(Some(filter))
+            })
+            .await?;
+        Ok(filter.expect("filters are always computed"))
+    }
 
-        // We already checked that it's not the current timeframe, so if we see
-        // StorageEntry::Open, seal it:
-        entry.seal();
+    /// Return the sorted TCNs generated by the reports in a sealed batch, as
+    /// their concatenation.
+    ///
+    /// Batches are only expanded when they are prepared, so this fails with
+    /// `503 Service Unavailable` until then.
+    #[instrument(skip(self))]
+    pub(crate) async fn tcns(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+    ) -> Result<Bytes, ErrReport> {
+        debug!(?timeframe, "got request for TCNs");
+        if !self.expanded {
+            return Err(eyre!("This server does not serve expanded TCNs"))
+                .set_status(StatusCode::NOT_FOUND)?;
+        }
+        check_published(timeframe)?;
+        self.blocking(move |store| match store.tcns(shard, timeframe)? {
+            Some(tcns) => Ok(tcns),
+            None => {
+                // Report a batch that does not exist or is not sealed as such.
+                store.get(shard, timeframe)?;
+                Err(eyre!("Report batch has not been expanded yet"))
+                    .set_status(StatusCode::SERVICE_UNAVAILABLE)?
+            }
+        })
+        .await
+    }
 
-        if let StorageEntry::Sealed(ref bytes) = entry {
-            Ok(bytes.clone())
-        } else {
-            Err(eyre!("Could not seal report batch"))
-                .set_status(StatusCode::INTERNAL_SERVER_ERROR)?
+    /// Describe the retained sealed batches of `shard`, oldest first.
+    #[instrument(skip(self))]
+    pub(crate) async fn batches(&self, shard: Shard) -> Result<Vec<BatchInfo>, ErrReport> {
+        debug!("got request for batch manifest");
+        let timeframes = self
+            .blocking(move |store| retained_sealed(store, shard))
+            .await?;
+        let mut batches = Vec::with_capacity(timeframes.len());
+        for timeframe in timeframes {
+            if let Some(info) = skip_expired(self.description(shard, timeframe).await)? {
+                batches.push(info);
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Describe the retained sealed batches of `shard`, oldest first, from a
+    /// thread where blocking is allowed.
+    fn sealed_batches(&self, shard: Shard) -> Result<Vec<BatchInfo>, ErrReport> {
+        let timeframes = retained_sealed(&*self.store, shard)?;
+        let mut batches = Vec::with_capacity(timeframes.len());
+        for timeframe in timeframes {
+            let info = self.describe(shard, timeframe, || self.store.get(shard, timeframe));
+            if let Some(info) = skip_expired(info)? {
+                batches.push(info);
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Return the sealed batches of `shard` with timeframes in `from..to`,
+    /// along with the timeframe the returned batches extend to.
+    ///
+    /// The range is clamped to the retained batches, and is cut short at the
+    /// current timeframe, at the first batch that has not been sealed yet, or
+    /// once the batches exceed `--max-range-mb`, so that a client can ask for
+    /// the rest of the range later.  Timeframes without reports are skipped.
+    #[instrument(skip(self))]
+    pub(crate) async fn get_range(
+        &self,
+        shard: Shard,
+        from: ReportTimestamp,
+        to: ReportTimestamp,
+    ) -> Result<(Vec<(ReportTimestamp, Bytes)>, ReportTimestamp), ErrReport> {
+        debug!("got request for range of entries");
+        if to < from {
+            return Err(eyre!(
+                "Range ends at {} before it starts at {}",
+                to.0,
+                from.0
+            ))
+            .set_status(StatusCode::BAD_REQUEST);
         }
+        let from = from.max(oldest_retained()?);
+        let mut until = to.min(ReportTimestamp::now()?).max(from);
+
+        self.blocking(move |store| {
+            // List open batches first: one sealed in the meantime then still
+            // ends the range, rather than being missed by both lists.
+            let open = store.open(shard)?;
+            if let Some(first) = open.into_iter().filter(|t| from <= *t && *t < until).min() {
+                until = first;
+            }
+            let mut timeframes: Vec<_> = store
+                .sealed(shard)?
+                .into_iter()
+                .filter(|t| from <= *t && *t < until)
+                .collect();
+            timeframes.sort_unstable();
+
+            let max_bytes = crate::OPTIONS.max_range_mb << 20;
+            let (mut batches, mut size) = (Vec::new(), 0);
+            for timeframe in timeframes {
+                let bytes = match skip_expired(store.get(shard, timeframe))? {
+                    Some(bytes) => bytes,
+                    None => continue,
+                };
+                size += bytes.len();
+                if size > max_bytes {
+                    if batches.is_empty() {
+                        return Err(eyre!(
+                            "Batch {} is larger than the {} MiB allowed in a range",
+                            timeframe.0,
+                            crate::OPTIONS.max_range_mb
+                        ))
+                        .set_status(StatusCode::PAYLOAD_TOO_LARGE);
+                    }
+                    until = timeframe;
+                    break;
+                }
+                batches.push((timeframe, bytes));
+            }
+            Ok((batches, until))
+        })
+        .await
+    }
+}
+
+/// A valid report under a new key, for tests.
+#[cfg(test)]
+fn test_report() -> SignedReport {
+    use tcn::{MemoType, ReportAuthorizationKey};
+
+    ReportAuthorizationKey::new(OsRng)
+        .create_report(MemoType::CoEpiV1, Vec::new(), 1, 10)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_duplicate_reports_are_stored_once() {
+    let storage = Storage::default();
+    let report = test_report();
+    for shard in &[Shard(1), Shard(1), Shard(2)] {
+        storage.save(*shard, report.clone(), None).await.unwrap();
+    }
+
+    let mut stored = Vec::new();
+    storage
+        .store
+        .for_each_report(&mut |shard, _, _| stored.push(shard))
+        .unwrap();
+    stored.sort_by_key(|shard| shard.0);
+    assert_eq!(stored, vec![Shard(1), Shard(2)]);
+}
+
+#[tokio::test]
+async fn test_overlapping_reports_are_rejected() {
+    use tcn::{MemoType, ReportAuthorizationKey};
+
+    let storage = Storage::default();
+    let rak = ReportAuthorizationKey::new(OsRng);
+    let report = |j_1, j_2| rak.create_report(MemoType::CoEpiV1, Vec::new(), j_1, j_2);
+
+    storage
+        .save(Shard(1), report(1, 10).unwrap(), None)
+        .await
+        .unwrap();
+    let err = storage
+        .save(Shard(1), report(5, 20).unwrap(), None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.0.context().status, StatusCode::CONFLICT);
+    storage
+        .save(Shard(1), report(11, 20).unwrap(), None)
+        .await
+        .unwrap();
+    // Other shards keep their own history.
+    storage
+        .save(Shard(2), report(5, 20).unwrap(), None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_tck_range_limits() {
+    use tcn::{MemoType, ReportAuthorizationKey};
+
+    let storage = Storage::default();
+    let max = crate::OPTIONS.max_tcks_per_report() as u16;
+    let status = |result: Result<_, ErrReport>| result.err().map(|e| e.0.context().status);
+
+    let rak = ReportAuthorizationKey::new(OsRng);
+    let report = rak.create_report(MemoType::CoEpiV1, Vec::new(), 1, max + 1);
+    assert_eq!(
+        status(storage.save(Shard(1), report.unwrap(), None).await),
+        Some(StatusCode::BAD_REQUEST)
+    );
+    let report = rak.create_report(MemoType::CoEpiV1, Vec::new(), 10, 9);
+    assert_eq!(
+        status(storage.save(Shard(1), report.unwrap(), None).await),
+        Some(StatusCode::BAD_REQUEST)
+    );
+    let report = rak.create_report(MemoType::CoEpiV1, Vec::new(), 1, max);
+    assert!(storage.save(Shard(1), report.unwrap(), None).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_batches_are_atomic() {
+    use tcn::{MemoType, ReportAuthorizationKey};
+
+    let storage = Storage::default();
+    let rak = ReportAuthorizationKey::new(OsRng);
+    let report = |j_1, j_2| {
+        rak.create_report(MemoType::CoEpiV1, Vec::new(), j_1, j_2)
+            .unwrap()
+    };
+    let stored = |storage: &Storage| {
+        let mut count = 0;
+        storage
+            .store
+            .for_each_report(&mut |_, _, _| count += 1)
+            .unwrap();
+        count
+    };
+
+    // The second report overlaps the first, so neither is stored.
+    let results = storage
+        .save_batch(Shard(1), vec![report(1, 10), report(5, 20)], None)
+        .await
+        .unwrap();
+    assert!(matches!(results[0], Ok(Saved::Withheld)));
+    assert_eq!(
+        results[1].as_ref().unwrap_err().0.context().status,
+        StatusCode::CONFLICT
+    );
+    assert_eq!(stored(&storage), 0);
+
+    let first = report(1, 10);
+    storage.save(Shard(1), first.clone(), None).await.unwrap();
+    let results = storage
+        .save_batch(Shard(1), vec![first, report(11, 20), report(21, 30)], None)
+        .await
+        .unwrap();
+    let results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+    assert!(matches!(
+        results.as_slice(),
+        [Saved::Duplicate(_), Saved::Stored(_), Saved::Stored(_)]
+    ));
+    assert_eq!(stored(&storage), 3);
+}
+
+#[tokio::test]
+async fn test_multi_shard_reports() {
+    use tcn::{MemoType, ReportAuthorizationKey};
+
+    let storage = Storage::default();
+    let rak = ReportAuthorizationKey::new(OsRng);
+    let report = |j_1, j_2| {
+        rak.create_report(MemoType::CoEpiV1, Vec::new(), j_1, j_2)
+            .unwrap()
+    };
+    let shards = |storage: &Storage| {
+        let mut shards = Vec::new();
+        storage
+            .store
+            .for_each_report(&mut |shard, _, _| shards.push(shard.0))
+            .unwrap();
+        shards.sort_unstable();
+        shards
+    };
+
+    storage.save(Shard(2), report(1, 10), None).await.unwrap();
+    // Overlapping the report in shard 2 keeps the report out of shard 1 too.
+    let err = storage
+        .save_to_shards(&[Shard(1), Shard(2)], report(5, 20), None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.0.context().status, StatusCode::CONFLICT);
+    assert_eq!(shards(&storage), vec![2]);
+
+    let second = report(11, 20);
+    let receipts = storage
+        .save_to_shards(&[Shard(3), Shard(1), Shard(3)], second.clone(), None)
+        .await
+        .unwrap();
+    let receipt_shards: Vec<_> = receipts.iter().map(|receipt| receipt.shard).collect();
+    assert_eq!(receipt_shards, vec![Shard(1), Shard(3)]);
+    // Shards that already hold the report still get a receipt.
+    let receipts = storage
+        .save_to_shards(&[Shard(1), Shard(2)], second, None)
+        .await
+        .unwrap();
+    assert_eq!(receipts.len(), 2);
+    assert_eq!(shards(&storage), vec![1, 2, 2, 3]);
+}
+
+#[test]
+fn test_pending_codes_are_resolved_on_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = DiskStore::open(dir.path()).unwrap();
+    let (stored, lost) = (test_report(), test_report());
+    let pending = |report: &SignedReport| CodeRecord {
+        expires_at: Some(u64::MAX),
+        consumed_by: Some(vec![ReportFields::of(report).signature]),
+        pending: true,
+        revoked: false,
+    };
+    store
+        .put_code(code_id("stored"), &pending(&stored))
+        .unwrap();
+    store.put_code(code_id("lost"), &pending(&lost)).unwrap();
+    store.save(Shard(1), ReportTimestamp(1), &[stored]).unwrap();
+
+    let storage = Storage::new(store).unwrap();
+    assert_eq!(
+        storage.outstanding_codes(),
+        vec![(code_id("lost"), Some(u64::MAX))]
+    );
+    let mut codes = DiskStore::open(dir.path()).unwrap().codes().unwrap();
+    codes.sort_by_key(|(id, _)| *id == code_id("lost"));
+    assert!(codes.iter().all(|(_, record)| !record.pending));
+    assert!(codes[0].1.consumed_by.is_some());
+    assert_eq!(codes[1].1.consumed_by, None);
+}
+
+#[test]
+fn test_revoked_codes_are_not_imported_again() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("wal");
+    let open = || Storage::new(MemoryStore::with_wal(&path).unwrap()).unwrap();
+
+    let storage = open();
+    assert_eq!(storage.import_codes(vec!["abc", "def"]).unwrap(), 2);
+    storage.revoke_code(code_id("abc")).unwrap();
+    drop(storage);
+
+    let storage = open();
+    assert_eq!(storage.import_codes(vec!["abc", "def"]).unwrap(), 0);
+    assert_eq!(storage.outstanding_codes(), vec![(code_id("def"), None)]);
+}
+
+#[tokio::test]
+async fn test_get_range() {
+    let storage = Storage::default();
+    let now = ReportTimestamp::now().unwrap();
+    let before = |n| ReportTimestamp(now.0 - n);
+    let timeframes = |(batches, until): (Vec<(ReportTimestamp, Bytes)>, _)| {
+        let timeframes: Vec<_> = batches.into_iter().map(|(t, _)| t).collect();
+        (timeframes, until)
+    };
+
+    storage
+        .store
+        .save(Shard(1), before(4), &[test_report()])
+        .unwrap();
+    storage
+        .store
+        .save(Shard(1), before(1), &[test_report()])
+        .unwrap();
+    storage.store.seal(now).unwrap();
+    // A batch left open stops the range, so that it can be fetched later.
+    storage
+        .store
+        .save(Shard(1), before(2), &[test_report()])
+        .unwrap();
+
+    let range = storage
+        .get_range(Shard(1), before(10), ReportTimestamp(now.0 + 10))
+        .await
+        .unwrap();
+    assert_eq!(timeframes(range), (vec![before(4)], before(2)));
+
+    storage.store.seal(now).unwrap();
+    let range = storage
+        .get_range(Shard(1), before(3), ReportTimestamp(now.0 + 10))
+        .await
+        .unwrap();
+    assert_eq!(timeframes(range), (vec![before(2), before(1)], now));
+    let range = storage.get_range(Shard(1), now, now).await.unwrap();
+    assert_eq!(timeframes(range), (vec![], now));
+    assert!(storage.get_range(Shard(1), now, before(1)).await.is_err());
+}
+
+#[tokio::test]
+async fn test_batch_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Storage::new(DiskStore::open(dir.path()).unwrap()).unwrap();
+    let now = ReportTimestamp::now().unwrap();
+
+    let old = ReportTimestamp(now.0 - 2);
+    storage
+        .store
+        .save(Shard(1), old, &[test_report(), test_report()])
+        .unwrap();
+    storage.store.seal(now).unwrap();
+    // Neither open batches nor other shards are listed.
+    storage.store.save(Shard(1), now, &[test_report()]).unwrap();
+    storage.store.save(Shard(2), old, &[test_report()]).unwrap();
+
+    let batches = storage.batches(Shard(1)).await.unwrap();
+    let sealed = storage.store.get(Shard(1), old).unwrap();
+    assert_eq!(batches, vec![BatchInfo::of(old, &sealed)]);
+    assert_eq!(batches[0].count, 2);
+    assert!(storage.batches(Shard(3)).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_static_export() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_dir = tempfile::tempdir().unwrap();
+    let (_, key) = crate::keys::generate(key_dir.path(), 0, None).unwrap();
+    let storage = Storage::default().with_keys(Keyring::load(key_dir.path()).unwrap());
+    let now = ReportTimestamp::now().unwrap();
+    let old = ReportTimestamp(now.0 - 2);
+    storage.store.save(Shard(1), old, &[test_report()]).unwrap();
+    storage.store.seal(now).unwrap();
+    storage.store.save(Shard(1), now, &[test_report()]).unwrap();
+
+    let export = Export::open(dir.path()).unwrap();
+    storage.export(&export).unwrap();
+    let shard_dir = dir.path().join("1");
+    let exported = std::fs::read(shard_dir.join(format!("{}.bin", old.0))).unwrap();
+    assert_eq!(exported, storage.store.get(Shard(1), old).unwrap());
+    let info = BatchInfo::of(old, &exported).sign(Shard(1), |message| storage.sign(message));
+    let index: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(shard_dir.join("index.json")).unwrap()).unwrap();
+    assert_eq!(index, serde_json::to_value(vec![info]).unwrap());
+    assert_eq!(index[0]["public_key"], hex::encode(key.public_key));
+    let signature = std::fs::read(shard_dir.join(format!("{}.bin.sig", old.0))).unwrap();
+    let signature: [u8; 64] = std::convert::TryInto::try_into(signature.as_slice()).unwrap();
+    assert!(key.verify(&signature, &info.message(Shard(1))));
+    // Open batches are not exported, and no temporary files are left behind.
+    let mut files: Vec<_> = std::fs::read_dir(&shard_dir)
+        .unwrap()
+        .map(|file| file.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            format!("{}.bin", old.0),
+            format!("{}.bin.sig", old.0),
+            "index.json".to_string()
+        ]
+    );
+
+    // Batches that were already published are not written again.
+    let sig = shard_dir.join(format!("{}.bin.sig", old.0));
+    std::fs::remove_file(&sig).unwrap();
+    storage.export(&export).unwrap();
+    assert!(!sig.exists());
+
+    // An exported batch that no longer matches its description is not
+    // published over, but the other shards are still published.
+    let path = shard_dir.join(format!("{}.bin", old.0));
+    std::fs::write(&path, b"tampered").unwrap();
+    std::fs::remove_file(shard_dir.join("index.json")).unwrap();
+    storage.store.save(Shard(2), old, &[test_report()]).unwrap();
+    storage.store.seal(now).unwrap();
+    let export = Export::open(dir.path()).unwrap();
+    storage.export(&export).unwrap();
+    assert!(!shard_dir.join("index.json").exists());
+    assert!(dir.path().join("2").join("index.json").exists());
+    std::fs::write(&path, &exported).unwrap();
+    storage.export(&export).unwrap();
+    assert!(sig.exists());
+
+    // Batches that are no longer retained are removed.
+    storage.store.expire(now).unwrap();
+    storage.export(&export).unwrap();
+    assert!(!path.exists());
+    assert!(!shard_dir.join(format!("{}.bin.sig", old.0)).exists());
+    assert_eq!(std::fs::read(shard_dir.join("index.json")).unwrap(), b"[]");
+}
+
+#[tokio::test]
+async fn test_log_batch_sealed_late() {
+    use crate::receipt::report_hash;
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Storage::new(DiskStore::open(dir.path()).unwrap()).unwrap();
+    let now = ReportTimestamp::now().unwrap();
+    let (late, early) = (ReportTimestamp(now.0 - 3), ReportTimestamp(now.0 - 2));
+    let report = test_report();
+    storage
+        .store
+        .save(Shard(1), late, std::slice::from_ref(&report))
+        .unwrap();
+    storage
+        .store
+        .save(Shard(1), early, &[test_report()])
+        .unwrap();
+
+    // The earlier batch fails to seal, while the later one is sealed and
+    // logged.
+    let blocked = dir.path().join("1").join(format!("{}.tmp", late.0));
+    std::fs::create_dir(&blocked).unwrap();
+    storage.store.seal(now).unwrap();
+    storage.extend_logs().unwrap();
+    assert_eq!(storage.tree_head(Shard(1)).await.unwrap().tree_size, 1);
+
+    // Once it is sealed, it is logged after the later batch.
+    std::fs::remove_dir(&blocked).unwrap();
+    storage.store.seal(now).unwrap();
+    storage.extend_logs().unwrap();
+    assert_eq!(storage.tree_head(Shard(1)).await.unwrap().tree_size, 2);
+    let proof = storage
+        .inclusion_proof(Shard(1), late, report_hash(&report))
+        .await
+        .unwrap();
+    assert_eq!(proof.entry_index, 1);
+}
+
+#[tokio::test]
+async fn test_signed_receipts() {
+    use crate::receipt::report_hash;
+    let dir = tempfile::tempdir().unwrap();
+    let (_, key) = crate::keys::generate(dir.path(), 0, None).unwrap();
+    let storage = Storage::default().with_keys(Keyring::load(dir.path()).unwrap());
+    let now = ReportTimestamp::now().unwrap();
+    let report = test_report();
+
+    let receipt = storage.save(Shard(1), report.clone(), None).await.unwrap();
+    assert_eq!((receipt.shard, receipt.timestamp), (Shard(1), now));
+    assert_eq!(receipt.public_key, Some(key.public_key));
+    assert!(key.verify(&receipt.signature.unwrap(), &receipt.message()));
+    // A resubmission gets a receipt for the same batch.
+    let again = storage.save(Shard(1), report, None).await.unwrap();
+    assert_eq!(again.timestamp, receipt.timestamp);
+
+    // The receipt can be checked against the published batch.
+    storage.store.seal(ReportTimestamp(now.0 + 1)).unwrap();
+    let (reports, _) = read_reports(&storage.store.get(Shard(1), now).unwrap());
+    assert!(reports
+        .iter()
+        .any(|report| report_hash(report) == receipt.report_sha256));
+
+    // Without a signing key, receipts are unsigned.
+    let storage = Storage::default();
+    let report = test_report();
+    let receipt = storage.save(Shard(1), report, None).await.unwrap();
+    assert_eq!((receipt.public_key, receipt.signature), (None, None));
+    let json = serde_json::to_value(&receipt).unwrap();
+    assert_eq!(json["report_sha256"], hex::encode(receipt.report_sha256));
+    assert!(json.get("signature").is_none());
+}
+
+#[tokio::test]
+async fn test_transparency_log() {
+    use crate::receipt::report_hash;
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Storage::new(DiskStore::open(dir.path()).unwrap()).unwrap();
+    let now = ReportTimestamp::now().unwrap();
+
+    let first = test_report();
+    storage
+        .store
+        .save(
+            Shard(1),
+            ReportTimestamp(now.0 - 3),
+            &[first.clone(), test_report()],
+        )
+        .unwrap();
+    storage.store.seal(ReportTimestamp(now.0 - 2)).unwrap();
+    storage.extend_logs().unwrap();
+    let old_head = storage.tree_head(Shard(1)).await.unwrap();
+    assert_eq!(old_head.tree_size, 1);
+
+    storage
+        .store
+        .save(Shard(1), ReportTimestamp(now.0 - 2), &[test_report()])
+        .unwrap();
+    storage.store.save(Shard(1), now, &[test_report()]).unwrap();
+    storage.store.seal(now).unwrap();
+    storage.extend_logs().unwrap();
+    storage.extend_logs().unwrap();
+    let head = storage.tree_head(Shard(1)).await.unwrap();
+    assert_eq!(head.tree_size, 2);
+    assert!(head.signature.is_none());
+
+    let proof = storage.consistency_proof(Shard(1), 1, 2).await.unwrap();
+    assert!(merkle::verify_consistency(
+        1,
+        2,
+        old_head.root_hash,
+        head.root_hash,
+        &proof.proof
+    ));
+    assert!(storage.consistency_proof(Shard(1), 1, 3).await.is_err());
+    assert!(storage.consistency_proof(Shard(1), 0, 2).await.is_err());
+
+    let proof = storage
+        .inclusion_proof(Shard(1), ReportTimestamp(now.0 - 3), report_hash(&first))
+        .await
+        .unwrap();
+    assert!(merkle::verify_inclusion(
+        proof.entry_index as usize,
+        proof.tree_size as usize,
+        proof.entry.leaf_hash(),
+        &proof.entry_proof,
+        head.root_hash
+    ));
+    let mut bytes = Vec::new();
+    first.write(&mut bytes).unwrap();
+    assert!(merkle::verify_inclusion(
+        proof.report_index as usize,
+        proof.entry.count as usize,
+        merkle::leaf_hash(&bytes),
+        &proof.report_proof,
+        proof.entry.reports_root
+    ));
+    assert!(storage
+        .inclusion_proof(Shard(1), ReportTimestamp(now.0 - 2), report_hash(&first))
+        .await
+        .is_err());
+
+    // The log outlives the batches it commits to, and survives restarts.
+    storage.store.expire(now).unwrap();
+    let storage = Storage::new(DiskStore::open(dir.path()).unwrap()).unwrap();
+    storage.extend_logs().unwrap();
+    assert_eq!(
+        storage.tree_head(Shard(1)).await.unwrap().root_hash,
+        head.root_hash
+    );
+    assert_eq!(storage.log(Shard(1)).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_transparency_log_survives_restart_with_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("wal");
+    let storage = Storage::new(MemoryStore::with_wal(&path).unwrap()).unwrap();
+    let now = ReportTimestamp::now().unwrap();
+
+    for timeframe in &[now.0 - 5, now.0 - 4] {
+        let timeframe = ReportTimestamp(*timeframe);
+        storage
+            .store
+            .save(Shard(1), timeframe, &[test_report()])
+            .unwrap();
     }
+    storage.store.seal(ReportTimestamp(now.0 - 3)).unwrap();
+    storage.extend_logs().unwrap();
+    let old_head = storage.tree_head(Shard(1)).await.unwrap();
+    assert_eq!(old_head.tree_size, 2);
+    // Expiring the logged batches drops them from the write-ahead log, but
+    // not their log entries.
+    storage.store.expire(ReportTimestamp(now.0 - 3)).unwrap();
+    drop(storage);
+
+    let storage = Storage::new(MemoryStore::with_wal(&path).unwrap()).unwrap();
+    assert_eq!(
+        storage.tree_head(Shard(1)).await.unwrap().root_hash,
+        old_head.root_hash
+    );
+    storage
+        .store
+        .save(Shard(1), ReportTimestamp(now.0 - 3), &[test_report()])
+        .unwrap();
+    storage.store.seal(ReportTimestamp(now.0 - 2)).unwrap();
+    storage.extend_logs().unwrap();
+    let head = storage.tree_head(Shard(1)).await.unwrap();
+    assert_eq!(head.tree_size, 3);
+    let proof = storage.consistency_proof(Shard(1), 2, 3).await.unwrap();
+    assert!(merkle::verify_consistency(
+        2,
+        3,
+        old_head.root_hash,
+        head.root_hash,
+        &proof.proof
+    ));
+}
+
+#[tokio::test]
+async fn test_batch_filter() {
+    let storage = Storage::default();
+    let now = ReportTimestamp::now().unwrap();
+    let old = ReportTimestamp(now.0 - 1);
+    let report = test_report();
+    let tcns: Vec<_> = report
+        .clone()
+        .verify()
+        .unwrap()
+        .temporary_contact_numbers()
+        .map(|tcn| tcn.0)
+        .collect();
+    storage.store.save(Shard(1), old, &[report]).unwrap();
+    storage.store.seal(now).unwrap();
+
+    let batch = storage.store.get(Shard(1), old).unwrap();
+    assert_eq!(expand_tcns(&batch), tcns);
+    let filter = storage.filter(Shard(1), old).await.unwrap();
+    assert_eq!(filter, filter::BloomFilter::of(&tcns).encode());
+    let status = |e: ErrReport| e.0.context().status;
+    assert_eq!(
+        storage.filter(Shard(1), now).await.map_err(status).err(),
+        Some(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(
+        storage.filter(Shard(2), old).await.map_err(status).err(),
+        Some(StatusCode::NOT_FOUND)
+    );
+}
+
+#[tokio::test]
+async fn test_batches_are_compressed_when_prepared() {
+    let dir = tempfile::tempdir().unwrap();
+    let now = ReportTimestamp::now().unwrap();
+    let old = ReportTimestamp(now.0 - 1);
+    let storage = Storage::new(DiskStore::open(dir.path()).unwrap())
+        .unwrap()
+        .with_expanded_tcns();
+    storage.store.save(Shard(1), old, &[test_report()]).unwrap();
+    storage.store.seal(now).unwrap();
+
+    // Requests never compress a batch themselves.
+    storage.get(Shard(1), old).await.unwrap();
+    assert_eq!(storage.store.encoded(Shard(1), old).unwrap(), None);
+
+    storage.prepare().unwrap();
+    let encoded = storage.store.encoded(Shard(1), old).unwrap().unwrap();
+    let batch = storage.store.get(Shard(1), old).unwrap();
+    assert_eq!(encoded, Encoded::of(&batch));
+    // Filters and expanded TCNs are saved too, so a restart does not expand
+    // the batch again.
+    let mut tcns = expand_tcns(&batch);
+    assert_eq!(
+        storage.store.filter(Shard(1), old).unwrap(),
+        Some(encode_filter(&tcns))
+    );
+    tcns.sort();
+    assert_eq!(
+        storage.store.tcns(Shard(1), old).unwrap(),
+        Some(Bytes::from(tcns.concat()))
+    );
+}
+
+#[tokio::test]
+async fn test_expanded_tcns() {
+    let now = ReportTimestamp::now().unwrap();
+    let old = ReportTimestamp(now.0 - 1);
+    let reports: Vec<_> = (0..2).map(|_| test_report()).collect();
+    let mut tcns: Vec<_> = reports
+        .iter()
+        .flat_map(|report| report.clone().verify().unwrap().temporary_contact_numbers())
+        .map(|tcn| tcn.0)
+        .collect();
+    tcns.sort();
+
+    let status = |e: ErrReport| e.0.context().status;
+    let storage = Storage::default();
+    storage.store.save(Shard(1), old, &reports).unwrap();
+    storage.store.seal(now).unwrap();
+    assert_eq!(
+        storage.tcns(Shard(1), old).await.map_err(status).err(),
+        Some(StatusCode::NOT_FOUND)
+    );
+
+    let storage = Storage::default().with_expanded_tcns();
+    storage.store.save(Shard(1), old, &reports).unwrap();
+    storage.store.seal(now).unwrap();
+    // Requests never expand a batch themselves.
+    assert_eq!(
+        storage.tcns(Shard(1), old).await.map_err(status).err(),
+        Some(StatusCode::SERVICE_UNAVAILABLE)
+    );
+    storage.prepare().unwrap();
+    let expanded = storage.tcns(Shard(1), old).await.unwrap();
+    assert_eq!(expanded, tcns.concat());
+    assert_eq!(
+        storage.tcns(Shard(1), now).await.map_err(status).err(),
+        Some(StatusCode::FORBIDDEN)
+    );
 }
@@ -0,0 +1,117 @@
+use super::{ReportTimestamp, Shard};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Values derived from sealed batches, such as their descriptions, compressed
+/// forms and filters.
+///
+/// Sealed batches never change, so each value is only computed once, and is
+/// kept until its batch expires.
+pub(crate) struct BatchCache<T> {
+    batches: RwLock<HashMap<(Shard, ReportTimestamp), T>>,
+}
+
+impl<T> Default for BatchCache<T> {
+    fn default() -> Self {
+        Self {
+            batches: RwLock::default(),
+        }
+    }
+}
+
+impl<T: Clone> BatchCache<T> {
+    /// Return the value for a sealed batch, if it has been computed.
+    pub(crate) fn get(&self, shard: Shard, timeframe: ReportTimestamp) -> Option<T> {
+        self.batches
+            .read()
+            .unwrap()
+            .get(&(shard, timeframe))
+            .cloned()
+    }
+
+    /// Return the value for a sealed batch, computing it with `compute` if
+    /// necessary.
+    pub(crate) fn get_or_insert_with<E>(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        compute: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        if let Some(value) = self.batches.read().unwrap().get(&(shard, timeframe)) {
+            return Ok(value.clone());
+        }
+        let value = compute()?;
+        self.batches
+            .write()
+            .unwrap()
+            .insert((shard, timeframe), value.clone());
+        Ok(value)
+    }
+
+    /// Return the value for a sealed batch, loading it with `load` if
+    /// necessary.  Batches that `load` has no value for are not remembered,
+    /// so that they are loaded again once they have one.
+    pub(crate) fn get_or_load<E>(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        load: impl FnOnce() -> Result<Option<T>, E>,
+    ) -> Result<Option<T>, E> {
+        if let Some(value) = self.batches.read().unwrap().get(&(shard, timeframe)) {
+            return Ok(Some(value.clone()));
+        }
+        let value = match load()? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        self.batches
+            .write()
+            .unwrap()
+            .insert((shard, timeframe), value.clone());
+        Ok(Some(value))
+    }
+
+    /// Remember the value for a sealed batch.
+    pub(crate) fn insert(&self, shard: Shard, timeframe: ReportTimestamp, value: T) {
+        self.batches
+            .write()
+            .unwrap()
+            .insert((shard, timeframe), value);
+    }
+
+    /// Forget every batch before `oldest`.
+    pub(crate) fn expire(&self, oldest: ReportTimestamp) {
+        self.batches
+            .write()
+            .unwrap()
+            .retain(|(_, timeframe), _| *timeframe >= oldest);
+    }
+}
+
+#[test]
+fn test_batch_cache() {
+    let cache = BatchCache::default();
+    let (old, new) = (ReportTimestamp(1), ReportTimestamp(2));
+    let computed = cache.get_or_insert_with(Shard(1), old, || Ok::<_, ()>(1));
+    assert_eq!(computed, Ok(1));
+    let cached = cache.get_or_insert_with(Shard(1), old, || Err(()));
+    assert_eq!(cached, Ok(1));
+
+    // Missing values are loaded again.
+    assert_eq!(
+        cache.get_or_load(Shard(1), new, || Ok::<_, ()>(None)),
+        Ok(None)
+    );
+    assert_eq!(
+        cache.get_or_load(Shard(1), new, || Ok::<_, ()>(Some(2))),
+        Ok(Some(2))
+    );
+    assert_eq!(cache.get_or_load(Shard(1), new, || Err(())), Ok(Some(2)));
+
+    cache.expire(new);
+    assert_eq!(
+        cache.get_or_insert_with(Shard(1), old, || Ok::<_, ()>(3)),
+        Ok(3)
+    );
+    assert_eq!(cache.get_or_load(Shard(1), new, || Err(())), Ok(Some(2)));
+}
@@ -0,0 +1,707 @@
+use super::log::ENTRY_LEN as LOG_ENTRY_LEN;
+use super::{
+    read_reports, serialize_shuffled, Encoded, ErrReport, LogEntry, ReportStore, ReportTimestamp,
+    Shard, SignedReport,
+};
+use crate::auth::{CodeId, CodeRecord};
+use crate::error::context::Status;
+use bytes::Bytes;
+use eyre::eyre;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+use warp::http::StatusCode;
+
+/// Stores batches as files under a root directory, one directory per shard:
+///
+/// - `{shard}/{timestamp}.open` is an append-only segment holding the
+///   serialized reports of a batch that is still accepting reports;
+/// - `{shard}/{timestamp}.sealed` is the shuffled batch, written once when the
+///   batch is sealed, after which the open segment is removed;
+/// - `{shard}/{timestamp}.encoded` holds the compressed forms of the sealed
+///   batch, as serialized by [`Encoded::encode`];
+/// - `{shard}/{timestamp}.filter` and `{shard}/{timestamp}.tcns` hold the
+///   encoded Bloom filter and the sorted TCNs of the sealed batch;
+/// - `{shard}/log` is the shard's append-only transparency log, a sequence of
+///   encoded [`LogEntry`]s;
+/// - `codes.log` is a log of changes to authorization codes, compacted to the
+///   current state of each code when batches expire.
+pub(crate) struct DiskStore {
+    root: PathBuf,
+    /// Serializes writes to each shard's segments.  Sealed segments are
+    /// immutable once renamed into place, so reads take no lock.
+    locks: Mutex<HashMap<Shard, Arc<Mutex<()>>>>,
+    /// Recently read sealed segments, so that popular batches are served
+    /// without reading them again.
+    cache: Mutex<SegmentCache>,
+    /// Serializes appends to and compaction of the authorization code log.
+    codes_lock: Mutex<()>,
+}
+
+pub(super) fn io_error(path: &Path) -> impl FnOnce(io::Error) -> ErrReport + '_ {
+    move |e| ErrReport::from(e).wrap_err(format!("I/O error on {}", path.display()))
+}
+
+impl DiskStore {
+    pub(crate) fn open(root: impl Into<PathBuf>) -> Result<Self, ErrReport> {
+        let root = root.into();
+        fs::create_dir_all(&root).map_err(io_error(&root))?;
+        let store = Self {
+            root,
+            locks: Mutex::default(),
+            cache: Mutex::new(SegmentCache::new(DEFAULT_CACHE_BYTES)),
+            codes_lock: Mutex::default(),
+        };
+        for (shard, timeframe) in store.segments("open")? {
+            repair_segment(&store.segment_path(shard, timeframe, "open"))?;
+        }
+        Ok(store)
+    }
+
+    /// Keep at most `bytes` of sealed segments in memory.
+    pub(crate) fn with_cache_size(self, bytes: usize) -> Self {
+        Self {
+            cache: Mutex::new(SegmentCache::new(bytes)),
+            ..self
+        }
+    }
+
+    /// List the batches that have a segment of the given kind.
+    fn segments(&self, kind: &str) -> Result<Vec<(Shard, ReportTimestamp)>, ErrReport> {
+        let mut segments = Vec::new();
+        for shard in self.shards()? {
+            for timeframe in self.shard_segments(shard, kind)? {
+                segments.push((shard, timeframe));
+            }
+        }
+        Ok(segments)
+    }
+
+    /// List the batches of `shard` that have a segment of the given kind.
+    fn shard_segments(&self, shard: Shard, kind: &str) -> Result<Vec<ReportTimestamp>, ErrReport> {
+        let dir = self.shard_dir(shard);
+        let segments = match fs::read_dir(&dir) {
+            Ok(segments) => segments,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(io_error(&dir)(e)),
+        };
+        let mut timeframes = Vec::new();
+        for segment in segments {
+            let segment = segment.map_err(io_error(&dir))?.path();
+            if segment.extension() != Some(kind.as_ref()) {
+                continue;
+            }
+            if let Some(timeframe) = segment
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse().ok())
+            {
+                timeframes.push(timeframe);
+            }
+        }
+        Ok(timeframes)
+    }
+
+    /// Durably append a line to the authorization code log.
+    fn append_code_log(&self, line: &str) -> Result<(), ErrReport> {
+        let _guard = self.codes_lock.lock().unwrap();
+        let path = self.root.join("codes.log");
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(io_error(&path))?;
+        log.write_all(format!("{}\n", line).as_bytes())
+            .map_err(io_error(&path))?;
+        log.sync_data().map_err(io_error(&path))?;
+        Ok(())
+    }
+
+    /// Read the current record of each code from the authorization code log,
+    /// along with the number of lines read.  The caller holds `codes_lock`.
+    fn read_code_log(&self) -> Result<(HashMap<CodeId, CodeRecord>, usize), ErrReport> {
+        let path = self.root.join("codes.log");
+        let log = match fs::read_to_string(&path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((HashMap::new(), 0)),
+            Err(e) => return Err(io_error(&path)(e)),
+        };
+
+        let mut codes = HashMap::new();
+        let mut lines = 0;
+        // Only complete lines were acknowledged; a torn final line is skipped.
+        let complete = &log[..log.rfind('\n').map_or(0, |i| i + 1)];
+        for line in complete.lines() {
+            lines += 1;
+            match parse_code_line(line) {
+                Some((id, record)) => {
+                    if codes
+                        .get(&id)
+                        .map_or(true, |earlier| record.replaces(earlier))
+                    {
+                        codes.insert(id, record);
+                    }
+                }
+                None => warn!(line, "ignoring malformed line in code log"),
+            }
+        }
+        Ok((codes, lines))
+    }
+
+    /// Rewrite the authorization code log with only the current record of
+    /// each code, if any of its lines have been superseded.
+    fn compact_code_log(&self) -> Result<(), ErrReport> {
+        let _guard = self.codes_lock.lock().unwrap();
+        let (codes, lines) = self.read_code_log()?;
+        if lines == codes.len() {
+            return Ok(());
+        }
+        let log: String = codes
+            .iter()
+            .map(|(id, record)| format!("{}\n", code_line(*id, record)))
+            .collect();
+        write_atomically(&self.root.join("codes.log"), log.as_bytes())?;
+        debug!(before = lines, kept = codes.len(), "compacted code log");
+        Ok(())
+    }
+
+    /// Shuffle the reports in an open segment into a sealed segment.
+    fn seal_segment(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<(), ErrReport> {
+        let open = self.segment_path(shard, timeframe, "open");
+        let sealed = self.segment_path(shard, timeframe, "sealed");
+        // A crash after sealing but before removing the open segment leaves
+        // both behind; the sealed segment has already been served, so keep it.
+        if !sealed.exists() {
+            debug!(path = %open.display(), "sealing segment");
+            let (mut reports, _) = read_reports(&fs::read(&open).map_err(io_error(&open))?);
+            let bytes = serialize_shuffled(&mut reports);
+            write_atomically(&sealed, &bytes)?;
+        }
+        fs::remove_file(&open).map_err(io_error(&open))?;
+        Ok(())
+    }
+
+    fn shard_lock(&self, shard: Shard) -> Arc<Mutex<()>> {
+        self.locks.lock().unwrap().entry(shard).or_default().clone()
+    }
+
+    fn shard_dir(&self, shard: Shard) -> PathBuf {
+        self.root.join(shard.0.to_string())
+    }
+
+    fn segment_path(&self, shard: Shard, timeframe: ReportTimestamp, kind: &str) -> PathBuf {
+        self.shard_dir(shard)
+            .join(format!("{}.{}", timeframe.0, kind))
+    }
+}
+
+/// The default size of the sealed segment cache.
+const DEFAULT_CACHE_BYTES: usize = 256 << 20;
+
+/// Sealed segments held in memory, evicting the least recently used once they
+/// exceed a total size.
+struct SegmentCache {
+    capacity: usize,
+    size: usize,
+    /// Counts accesses, to order segments by when they were last used.
+    clock: u64,
+    segments: HashMap<(Shard, ReportTimestamp), (Bytes, u64)>,
+}
+
+impl SegmentCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            clock: 0,
+            segments: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: (Shard, ReportTimestamp)) -> Option<Bytes> {
+        self.clock += 1;
+        let (bytes, used) = self.segments.get_mut(&key)?;
+        *used = self.clock;
+        Some(bytes.clone())
+    }
+
+    fn insert(&mut self, key: (Shard, ReportTimestamp), bytes: Bytes) {
+        if bytes.len() > self.capacity {
+            return;
+        }
+        self.clock += 1;
+        self.size += bytes.len();
+        if let Some((old, _)) = self.segments.insert(key, (bytes, self.clock)) {
+            self.size -= old.len();
+        }
+        while self.size > self.capacity {
+            let oldest = *self
+                .segments
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key)
+                .unwrap();
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &(Shard, ReportTimestamp)) {
+        if let Some((bytes, _)) = self.segments.remove(key) {
+            self.size -= bytes.len();
+        }
+    }
+
+    /// Forget every segment before `oldest`.
+    fn expire(&mut self, oldest: ReportTimestamp) {
+        let expired: Vec<_> = self
+            .segments
+            .keys()
+            .filter(|(_, timeframe)| *timeframe < oldest)
+            .copied()
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+}
+
+/// Truncate any partial report at the end of an open segment, so that new
+/// appends are not hidden behind it.
+fn repair_segment(path: &Path) -> Result<(), ErrReport> {
+    let bytes = fs::read(path).map_err(io_error(path))?;
+    let (_, valid_len) = read_reports(&bytes);
+    if valid_len < bytes.len() {
+        warn!(path = %path.display(), valid_len, "truncating corrupt tail of segment");
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(io_error(path))?;
+        file.set_len(valid_len as u64).map_err(io_error(path))?;
+        file.sync_all().map_err(io_error(path))?;
+    }
+    Ok(())
+}
+
+/// Format a line of the code log, as parsed by [`parse_code_line`].
+fn code_line(id: CodeId, record: &CodeRecord) -> String {
+    let expires_at = record
+        .expires_at
+        .map_or_else(|| "-".to_string(), |t| t.to_string());
+    let consumed_by = record.consumed_by.as_ref().map_or_else(
+        || "-".to_string(),
+        |signatures| {
+            signatures
+                .iter()
+                .map(hex::encode)
+                .collect::<Vec<_>>()
+                .join(",")
+        },
+    );
+    format!(
+        "put {} {} {}{}",
+        hex::encode(id),
+        expires_at,
+        consumed_by,
+        match record {
+            CodeRecord { revoked: true, .. } => " revoked",
+            CodeRecord { pending: true, .. } => " pending",
+            _ => "",
+        }
+    )
+}
+
+/// Parse a line of the code log:
+/// `put <id> <expires_at or -> <comma-separated signatures or -> [pending|revoked]`.
+fn parse_code_line(line: &str) -> Option<(CodeId, CodeRecord)> {
+    fn decode<T: Default + AsMut<[u8]>>(hex: &str) -> Option<T> {
+        let mut bytes = T::default();
+        hex::decode_to_slice(hex, bytes.as_mut()).ok()?;
+        Some(bytes)
+    }
+
+    let mut fields = line.split(' ');
+    if fields.next()? != "put" {
+        return None;
+    }
+    let id = decode::<CodeId>(fields.next()?)?;
+    let expires_at = match fields.next()? {
+        "-" => None,
+        t => Some(t.parse().ok()?),
+    };
+    let consumed_by = match fields.next()? {
+        "-" => None,
+        signatures => Some(
+            signatures
+                .split(',')
+                .map(|sig| {
+                    let mut bytes = [0; 64];
+                    hex::decode_to_slice(sig, &mut bytes).ok()?;
+                    Some(bytes)
+                })
+                .collect::<Option<Vec<_>>>()?,
+        ),
+    };
+    let (pending, revoked) = match fields.next() {
+        None => (false, false),
+        Some("pending") => (true, false),
+        Some("revoked") => (false, true),
+        Some(_) => return None,
+    };
+    Some((
+        id,
+        CodeRecord {
+            expires_at,
+            consumed_by,
+            pending,
+            revoked,
+        },
+    ))
+}
+
+/// Read the file at `path`, if it exists.
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, ErrReport> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(io_error(path)(e)),
+    }
+}
+
+/// Write `bytes` to `path` so that readers never observe a partial file.
+pub(super) fn write_atomically(path: &Path, bytes: &[u8]) -> Result<(), ErrReport> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp).map_err(io_error(&tmp))?;
+    file.write_all(bytes).map_err(io_error(&tmp))?;
+    file.sync_all().map_err(io_error(&tmp))?;
+    fs::rename(&tmp, path).map_err(io_error(path))?;
+    Ok(())
+}
+
+impl ReportStore for DiskStore {
+    fn save(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        reports: &[SignedReport],
+    ) -> Result<(), ErrReport> {
+        let lock = self.shard_lock(shard);
+        let _guard = lock.lock().unwrap();
+        if self.segment_path(shard, timeframe, "sealed").exists() {
+            return Err(eyre!("Current entry is already sealed. Is time broken?"))
+                .set_status(StatusCode::CONFLICT)?;
+        }
+
+        let mut bytes = Vec::new();
+        for report in reports {
+            report
+                .write(&mut bytes)
+                .expect("report serialization should be infallible");
+        }
+
+        let dir = self.shard_dir(shard);
+        fs::create_dir_all(&dir).map_err(io_error(&dir))?;
+        let path = self.segment_path(shard, timeframe, "open");
+        let mut segment = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(io_error(&path))?;
+        let start = segment.metadata().map_err(io_error(&path))?.len();
+        if let Err(e) = segment.write_all(&bytes).and_then(|_| segment.sync_data()) {
+            // Drop any reports that were written, so that none of them are
+            // stored.
+            segment.set_len(start).map_err(io_error(&path))?;
+            return Err(io_error(&path)(e));
+        }
+        Ok(())
+    }
+
+    fn seal(&self, current: ReportTimestamp) -> Result<Vec<(Shard, ReportTimestamp)>, ErrReport> {
+        let mut sealed = Vec::new();
+        for (shard, timeframe) in self.segments("open")? {
+            if timeframe < current {
+                let lock = self.shard_lock(shard);
+                let _guard = lock.lock().unwrap();
+                // The segment stays open, and is sealed on the next attempt.
+                match self.seal_segment(shard, timeframe) {
+                    Ok(()) => sealed.push((shard, timeframe)),
+                    Err(error) => warn!(?error, ?shard, ?timeframe, "failed to seal segment"),
+                }
+            }
+        }
+        Ok(sealed)
+    }
+
+    fn expire(&self, oldest: ReportTimestamp) -> Result<(), ErrReport> {
+        for kind in &["open", "sealed", "encoded", "filter", "tcns"] {
+            for (shard, timeframe) in self.segments(kind)? {
+                if timeframe < oldest {
+                    let lock = self.shard_lock(shard);
+                    let _guard = lock.lock().unwrap();
+                    let path = self.segment_path(shard, timeframe, kind);
+                    debug!(path = %path.display(), "expiring segment");
+                    fs::remove_file(&path).map_err(io_error(&path))?;
+                }
+            }
+        }
+        self.cache.lock().unwrap().expire(oldest);
+        self.compact_code_log()
+    }
+
+    fn for_each_report(
+        &self,
+        visit: &mut dyn FnMut(Shard, ReportTimestamp, SignedReport),
+    ) -> Result<(), ErrReport> {
+        for kind in &["open", "sealed"] {
+            for (shard, timeframe) in self.segments(kind)? {
+                let path = self.segment_path(shard, timeframe, kind);
+                let (reports, _) = read_reports(&fs::read(&path).map_err(io_error(&path))?);
+                for report in reports {
+                    visit(shard, timeframe, report);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn put_code(&self, id: CodeId, record: &CodeRecord) -> Result<(), ErrReport> {
+        self.append_code_log(&code_line(id, record))
+    }
+
+    fn codes(&self) -> Result<Vec<(CodeId, CodeRecord)>, ErrReport> {
+        let _guard = self.codes_lock.lock().unwrap();
+        let (codes, _) = self.read_code_log()?;
+        Ok(codes.into_iter().collect())
+    }
+
+    fn append_log(&self, shard: Shard, entry: &LogEntry) -> Result<(), ErrReport> {
+        let lock = self.shard_lock(shard);
+        let _guard = lock.lock().unwrap();
+        let path = self.shard_dir(shard).join("log");
+        let mut log = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(io_error(&path))?;
+        // Drop any entry left partially written by a crash.
+        let len = log.metadata().map_err(io_error(&path))?.len();
+        let valid_len = len - len % LOG_ENTRY_LEN as u64;
+        if valid_len < len {
+            warn!(path = %path.display(), "truncating partial transparency log entry");
+            log.set_len(valid_len).map_err(io_error(&path))?;
+        }
+        log.seek(SeekFrom::Start(valid_len))
+            .map_err(io_error(&path))?;
+        log.write_all(&entry.encode()).map_err(io_error(&path))?;
+        log.sync_data().map_err(io_error(&path))?;
+        Ok(())
+    }
+
+    fn log(&self, shard: Shard) -> Result<Vec<LogEntry>, ErrReport> {
+        let path = self.shard_dir(shard).join("log");
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(io_error(&path)(e)),
+        };
+        Ok(bytes
+            .chunks_exact(LOG_ENTRY_LEN)
+            .map(|entry| LogEntry::decode(entry.try_into().unwrap()))
+            .collect())
+    }
+
+    fn shards(&self) -> Result<Vec<Shard>, ErrReport> {
+        let mut shards = Vec::new();
+        for shard_dir in fs::read_dir(&self.root).map_err(io_error(&self.root))? {
+            let shard_dir = shard_dir.map_err(io_error(&self.root))?.path();
+            match shard_dir
+                .file_name()
+                .and_then(|name| name.to_str()?.parse().ok())
+            {
+                Some(shard) if shard_dir.is_dir() => shards.push(shard),
+                _ => continue,
+            }
+        }
+        Ok(shards)
+    }
+
+    fn sealed(&self, shard: Shard) -> Result<Vec<ReportTimestamp>, ErrReport> {
+        self.shard_segments(shard, "sealed")
+    }
+
+    fn open(&self, shard: Shard) -> Result<Vec<ReportTimestamp>, ErrReport> {
+        self.shard_segments(shard, "open")
+    }
+
+    fn save_encoded(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        encoded: &Encoded,
+    ) -> Result<(), ErrReport> {
+        write_atomically(
+            &self.segment_path(shard, timeframe, "encoded"),
+            &encoded.encode(),
+        )
+    }
+
+    fn encoded(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+    ) -> Result<Option<Encoded>, ErrReport> {
+        let path = self.segment_path(shard, timeframe, "encoded");
+        let bytes = match read_if_exists(&path)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        match Encoded::decode(&bytes) {
+            Some(encoded) => Ok(Some(encoded)),
+            None => {
+                warn!(path = %path.display(), "ignoring corrupt compressed batch");
+                Ok(None)
+            }
+        }
+    }
+
+    fn save_filter(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        filter: &[u8],
+    ) -> Result<(), ErrReport> {
+        write_atomically(&self.segment_path(shard, timeframe, "filter"), filter)
+    }
+
+    fn filter(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<Option<Bytes>, ErrReport> {
+        let path = self.segment_path(shard, timeframe, "filter");
+        Ok(read_if_exists(&path)?.map(Bytes::from))
+    }
+
+    fn save_tcns(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        tcns: &[u8],
+    ) -> Result<(), ErrReport> {
+        write_atomically(&self.segment_path(shard, timeframe, "tcns"), tcns)
+    }
+
+    fn has_tcns(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<bool, ErrReport> {
+        Ok(self.segment_path(shard, timeframe, "tcns").exists())
+    }
+
+    fn tcns(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<Option<Bytes>, ErrReport> {
+        let path = self.segment_path(shard, timeframe, "tcns");
+        Ok(read_if_exists(&path)?.map(Bytes::from))
+    }
+
+    fn get(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<Bytes, ErrReport> {
+        if let Some(bytes) = self.cache.lock().unwrap().get((shard, timeframe)) {
+            return Ok(bytes);
+        }
+
+        let sealed = self.segment_path(shard, timeframe, "sealed");
+        if sealed.exists() {
+            let bytes = Bytes::from(fs::read(&sealed).map_err(io_error(&sealed))?);
+            self.cache
+                .lock()
+                .unwrap()
+                .insert((shard, timeframe), bytes.clone());
+            return Ok(bytes);
+        }
+
+        if self.segment_path(shard, timeframe, "open").exists() {
+            Err(eyre!("Report batch has not been sealed yet"))
+                .set_status(StatusCode::SERVICE_UNAVAILABLE)?
+        } else {
+            Err(eyre!("No entries for this timeframe")).set_status(StatusCode::NOT_FOUND)?
+        }
+    }
+}
+
+#[test]
+fn test_disk_store_seals_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = DiskStore::open(dir.path()).unwrap();
+    let (shard, timeframe) = (Shard(1), ReportTimestamp(7));
+    for _ in 0..3 {
+        let report = super::test_report();
+        store.save(shard, timeframe, &[report]).unwrap();
+    }
+
+    assert!(store.get(shard, timeframe).is_err());
+    store.seal(ReportTimestamp(8)).unwrap();
+    let sealed = store.get(shard, timeframe).unwrap();
+    assert_eq!(read_reports(&sealed).0.len(), 3);
+    // Reopening and resealing serves the same bytes rather than reshuffling.
+    let store = DiskStore::open(dir.path()).unwrap();
+    store.seal(ReportTimestamp(8)).unwrap();
+    assert_eq!(store.get(shard, timeframe).unwrap(), sealed);
+    assert!(store
+        .save(shard, timeframe, &read_reports(&sealed).0[..1])
+        .is_err());
+
+    assert_eq!(store.encoded(shard, timeframe).unwrap(), None);
+    let encoded = Encoded::of(&sealed);
+    store.save_encoded(shard, timeframe, &encoded).unwrap();
+    assert_eq!(store.encoded(shard, timeframe).unwrap(), Some(encoded));
+    store.save_filter(shard, timeframe, b"filter").unwrap();
+    store.save_tcns(shard, timeframe, b"tcns").unwrap();
+    assert!(store.has_tcns(shard, timeframe).unwrap());
+    assert_eq!(
+        store.filter(shard, timeframe).unwrap(),
+        Some(Bytes::from_static(b"filter"))
+    );
+    store.expire(ReportTimestamp(8)).unwrap();
+    assert_eq!(store.encoded(shard, timeframe).unwrap(), None);
+    assert_eq!(store.filter(shard, timeframe).unwrap(), None);
+    assert_eq!(store.tcns(shard, timeframe).unwrap(), None);
+}
+
+#[test]
+fn test_segment_cache_evicts_least_recently_used() {
+    let key = |t| (Shard(1), ReportTimestamp(t));
+    let mut cache = SegmentCache::new(10);
+    cache.insert(key(1), Bytes::from(vec![0; 4]));
+    cache.insert(key(2), Bytes::from(vec![0; 4]));
+    assert!(cache.get(key(1)).is_some());
+    cache.insert(key(3), Bytes::from(vec![0; 4]));
+    assert!(cache.get(key(2)).is_none());
+    assert!(cache.get(key(1)).is_some());
+    assert_eq!(cache.size, 8);
+
+    cache.insert(key(4), Bytes::from(vec![0; 11]));
+    assert!(cache.get(key(4)).is_none());
+    cache.expire(ReportTimestamp(3));
+    assert!(cache.get(key(1)).is_none());
+    assert_eq!(cache.size, 4);
+}
+
+#[test]
+fn test_disk_store_compacts_code_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = DiskStore::open(dir.path()).unwrap();
+    let pending = CodeRecord {
+        pending: true,
+        ..CodeRecord::default()
+    };
+    let revoked = CodeRecord {
+        revoked: true,
+        ..CodeRecord::default()
+    };
+    store.put_code([1; 32], &CodeRecord::default()).unwrap();
+    store.put_code([1; 32], &pending).unwrap();
+    store.put_code([2; 32], &revoked).unwrap();
+
+    store.expire(ReportTimestamp(1)).unwrap();
+    let log = fs::read_to_string(dir.path().join("codes.log")).unwrap();
+    assert_eq!(log.lines().count(), 2);
+    let mut codes = DiskStore::open(dir.path()).unwrap().codes().unwrap();
+    codes.sort_by_key(|(id, _)| *id);
+    assert_eq!(codes, vec![([1; 32], pending), ([2; 32], revoked)]);
+}
@@ -0,0 +1,144 @@
+use bytes::Bytes;
+use std::convert::TryInto;
+use std::io::Write;
+
+/// A content encoding that sealed batches are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Encoding {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Every encoding, in order of preference.
+    pub(crate) const ALL: [Encoding; 3] = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+    /// The name of the encoding in `Accept-Encoding` and `Content-Encoding`.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The byte identifying the encoding in [`Encoded::encode`].
+    fn id(self) -> u8 {
+        match self {
+            Encoding::Zstd => 0,
+            Encoding::Brotli => 1,
+            Encoding::Gzip => 2,
+        }
+    }
+
+    fn compress(self, batch: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Zstd => zstd::encode_all(batch, 19).expect("compressing in memory"),
+            Encoding::Brotli => {
+                let mut compressed = Vec::new();
+                let params = brotli::enc::BrotliEncoderParams::default();
+                brotli::BrotliCompress(&mut &batch[..], &mut compressed, &params)
+                    .expect("compressing in memory");
+                compressed
+            }
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(batch).expect("compressing in memory");
+                encoder.finish().expect("compressing in memory")
+            }
+        }
+    }
+}
+
+/// The compressed forms of a sealed batch.
+///
+/// Encodings that would not make the batch smaller are left out, since
+/// reports are mostly keys and signatures that do not compress well.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Encoded {
+    forms: Vec<(Encoding, Bytes)>,
+}
+
+impl Encoded {
+    pub(crate) fn of(batch: &[u8]) -> Self {
+        let forms = Encoding::ALL
+            .iter()
+            .map(|encoding| (*encoding, encoding.compress(batch)))
+            .filter(|(_, compressed)| compressed.len() < batch.len())
+            .map(|(encoding, compressed)| (encoding, compressed.into()))
+            .collect();
+        Self { forms }
+    }
+
+    /// Return the batch compressed with `encoding`, if it is smaller that way.
+    pub(crate) fn get(&self, encoding: Encoding) -> Option<Bytes> {
+        self.forms
+            .iter()
+            .find(|(e, _)| *e == encoding)
+            .map(|(_, compressed)| compressed.clone())
+    }
+
+    /// The encodings the batch is available in.
+    pub(crate) fn encodings(&self) -> impl Iterator<Item = Encoding> + '_ {
+        self.forms.iter().map(|(encoding, _)| *encoding)
+    }
+
+    /// Serialize the compressed forms, as `[encoding: u8][length: u32 LE][bytes]`
+    /// for each of them.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (encoding, compressed) in &self.forms {
+            bytes.push(encoding.id());
+            bytes.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(compressed);
+        }
+        bytes
+    }
+
+    /// Parse compressed forms serialized by [`Encoded::encode`].
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut forms = Vec::new();
+        let mut rest = bytes;
+        while let Some((&id, tail)) = rest.split_first() {
+            let encoding = *Encoding::ALL.iter().find(|e| e.id() == id)?;
+            let len = u32::from_le_bytes(tail.get(..4)?.try_into().unwrap()) as usize;
+            let compressed = tail.get(4..4 + len)?;
+            forms.push((encoding, Bytes::copy_from_slice(compressed)));
+            rest = &tail[4 + len..];
+        }
+        Some(Self { forms })
+    }
+}
+
+#[test]
+fn test_encodings_round_trip() {
+    use std::io::Read;
+
+    let batch = b"report ".repeat(100);
+    let encoded = Encoded::of(&batch);
+    assert_eq!(encoded.encodings().collect::<Vec<_>>(), Encoding::ALL);
+
+    let mut decoded = Vec::new();
+    let zstd = encoded.get(Encoding::Zstd).unwrap();
+    assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), batch);
+    let brotli = encoded.get(Encoding::Brotli).unwrap();
+    brotli::Decompressor::new(&brotli[..], 4096)
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, batch);
+    decoded.clear();
+    let gzip = encoded.get(Encoding::Gzip).unwrap();
+    flate2::read::GzDecoder::new(&gzip[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, batch);
+
+    // Incompressible batches are only served as they are.
+    assert_eq!(Encoded::of(&[0x5a]).encodings().count(), 0);
+
+    assert_eq!(Encoded::decode(&encoded.encode()), Some(encoded));
+    assert_eq!(Encoded::decode(&[]), Some(Encoded::default()));
+    assert_eq!(Encoded::decode(&[7, 0, 0, 0, 0]), None);
+}
@@ -0,0 +1,142 @@
+use super::disk::{io_error, write_atomically};
+use super::{BatchInfo, ErrReport, ReportTimestamp, Shard};
+use bytes::Bytes;
+use eyre::eyre;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::debug;
+
+/// Publishes sealed batches as static files under a root directory, so that
+/// a static file server or object store can serve downloads:
+///
+/// - `{shard}/{timestamp}.bin` is a sealed batch, as served by `get_reports`;
+/// - `{shard}/{timestamp}.bin.sig` is the signature of the batch's
+///   description, if it is signed;
+/// - `{shard}/index.json` describes the shard's exported batches, as served
+///   by `batches`.
+///
+/// Every file is written to a temporary name and renamed into place, and a
+/// shard's index is only updated once the batches it lists have been written.
+pub(crate) struct Export {
+    root: PathBuf,
+    /// The batches last published for each shard, which are known to match
+    /// their files.  Shards are scanned and their files checked the first
+    /// time they are published after opening.
+    published: Mutex<HashMap<Shard, HashMap<u64, BatchInfo>>>,
+}
+
+impl Export {
+    pub(crate) fn open(root: impl Into<PathBuf>) -> Result<Self, ErrReport> {
+        let root = root.into();
+        fs::create_dir_all(&root).map_err(io_error(&root))?;
+        Ok(Self {
+            root,
+            published: Mutex::default(),
+        })
+    }
+
+    /// Publish the sealed batches of `shard` described by `batches`, calling
+    /// `batch` for the contents of those not yet written, and remove any
+    /// other batches of the shard.
+    ///
+    /// Only batches that changed since the shard was last published are
+    /// written or removed.  Fails without updating the index if a batch that
+    /// was already written does not match its description.
+    pub(crate) fn publish(
+        &self,
+        shard: Shard,
+        batches: &[BatchInfo],
+        mut batch: impl FnMut(ReportTimestamp) -> Result<Bytes, ErrReport>,
+    ) -> Result<(), ErrReport> {
+        let dir = self.root.join(shard.0.to_string());
+        let previous = self.published.lock().unwrap().get(&shard).cloned();
+        if previous.is_none() {
+            fs::create_dir_all(&dir).map_err(io_error(&dir))?;
+        }
+        let mut changed = previous
+            .as_ref()
+            .map_or(true, |previous| previous.len() != batches.len());
+        for info in batches {
+            let published = previous
+                .as_ref()
+                .and_then(|previous| previous.get(&info.timestamp));
+            if published == Some(info) {
+                continue;
+            }
+            changed = true;
+            // A batch that was already published was checked then, and only
+            // its signature can have changed since.
+            if published.is_none() {
+                let path = dir.join(format!("{}.bin", info.timestamp));
+                if path.exists() {
+                    let exported = fs::read(&path).map_err(io_error(&path))?;
+                    if Sha256::digest(&exported).as_slice() != info.sha256 {
+                        return Err(eyre!(
+                            "Exported batch {} does not match the sealed batch",
+                            path.display()
+                        )
+                        .into());
+                    }
+                } else {
+                    debug!(path = %path.display(), "exporting batch");
+                    write_atomically(&path, &batch(ReportTimestamp(info.timestamp))?)?;
+                }
+            }
+            if let Some(signature) = info.signature {
+                write_atomically(&dir.join(format!("{}.bin.sig", info.timestamp)), &signature)?;
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+        let index = serde_json::to_vec(batches)?;
+        write_atomically(&dir.join("index.json"), &index)?;
+
+        // Batches are only removed once the index no longer lists them.
+        let listed = |timestamp| batches.iter().any(|b| b.timestamp == timestamp);
+        match previous {
+            Some(ref previous) => {
+                for timestamp in previous.keys().filter(|t| !listed(**t)) {
+                    for name in &[
+                        format!("{}.bin", timestamp),
+                        format!("{}.bin.sig", timestamp),
+                    ] {
+                        remove_exported(dir.join(name))?;
+                    }
+                }
+            }
+            None => {
+                for file in fs::read_dir(&dir).map_err(io_error(&dir))? {
+                    let path = file.map_err(io_error(&dir))?.path();
+                    let timestamp = path.file_name().and_then(|name| {
+                        let name = name.to_str()?;
+                        let stem = name
+                            .strip_suffix(".bin")
+                            .or_else(|| name.strip_suffix(".bin.sig"))?;
+                        stem.parse::<u64>().ok()
+                    });
+                    if timestamp.map_or(false, |timestamp| !listed(timestamp)) {
+                        remove_exported(path)?;
+                    }
+                }
+            }
+        }
+
+        let batches = batches.iter().map(|info| (info.timestamp, *info)).collect();
+        self.published.lock().unwrap().insert(shard, batches);
+        Ok(())
+    }
+}
+
+/// Remove an exported file, if it exists.
+fn remove_exported(path: PathBuf) -> Result<(), ErrReport> {
+    debug!(path = %path.display(), "removing exported batch");
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_error(&path)(e)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,90 @@
+use std::convert::TryInto;
+
+/// The number of filter bits per TCN, which with [`HASH_COUNT`] hashes gives
+/// a false positive rate of about 1%.
+const BITS_PER_TCN: usize = 10;
+/// The number of bits set for each TCN.
+const HASH_COUNT: u8 = 7;
+
+/// A Bloom filter over the TCNs generated by a batch's reports.
+///
+/// TCNs are already hash outputs, so the bit positions of a TCN are derived
+/// from the TCN itself by double hashing: with `h1` and `h2` its first and
+/// last 8 bytes as `u64 LE`, and `h2` made odd, the `i`-th position is
+/// `(h1 + i * h2) mod bit_count`, using wrapping arithmetic.  The filter is
+/// encoded as
+///
+/// ```text
+/// [hash_count: u8][bit_count: u32 LE][bits: bit_count / 8]
+/// ```
+///
+/// where bit `i` is bit `i % 8` of byte `i / 8`.
+pub(crate) struct BloomFilter {
+    hash_count: u8,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    pub(crate) fn of(tcns: &[[u8; 16]]) -> Self {
+        let bytes = (tcns.len() * BITS_PER_TCN).div_ceil(8).max(1);
+        let mut filter = Self {
+            hash_count: HASH_COUNT,
+            bits: vec![0; bytes],
+        };
+        for tcn in tcns {
+            for position in filter.positions(tcn).collect::<Vec<_>>() {
+                filter.bits[position / 8] |= 1 << (position % 8);
+            }
+        }
+        filter
+    }
+
+    fn positions(&self, tcn: &[u8; 16]) -> impl Iterator<Item = usize> {
+        let bit_count = (self.bits.len() * 8) as u64;
+        let h1 = u64::from_le_bytes(tcn[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(tcn[8..].try_into().unwrap()) | 1;
+        (0..u64::from(self.hash_count))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+
+    /// Whether `tcn` may be in the filter.
+    #[cfg(test)]
+    pub(crate) fn contains(&self, tcn: &[u8; 16]) -> bool {
+        self.positions(tcn)
+            .all(|position| self.bits[position / 8] & (1 << (position % 8)) != 0)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(5 + self.bits.len());
+        bytes.push(self.hash_count);
+        bytes.extend_from_slice(&((self.bits.len() * 8) as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.bits);
+        bytes
+    }
+}
+
+#[test]
+fn test_bloom_filter() {
+    use rand::RngCore;
+
+    let mut rng = rand::rngs::OsRng;
+    let mut tcn = || {
+        let mut tcn = [0; 16];
+        rng.fill_bytes(&mut tcn);
+        tcn
+    };
+    let tcns: Vec<_> = (0..1000).map(|_| tcn()).collect();
+    let filter = BloomFilter::of(&tcns);
+    assert!(tcns.iter().all(|t| filter.contains(t)));
+    let false_positives = (0..10_000).filter(|_| filter.contains(&tcn())).count();
+    assert!(false_positives < 300, "{} false positives", false_positives);
+
+    let encoded = filter.encode();
+    assert_eq!(encoded[0], HASH_COUNT);
+    assert_eq!(encoded[1..5], (10_000u32).to_le_bytes());
+    assert_eq!(encoded.len(), 5 + 1250);
+    assert_eq!(
+        BloomFilter::of(&[]).encode(),
+        vec![HASH_COUNT, 8, 0, 0, 0, 0]
+    );
+}
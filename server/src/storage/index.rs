@@ -0,0 +1,96 @@
+use super::{ErrReport, ReportTimestamp, Shard, SignedReport};
+use crate::error::context::Status;
+use crate::report::ReportFields;
+use eyre::eyre;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use warp::http::StatusCode;
+
+/// The reports accepted into each shard within the retention window.
+#[derive(Default)]
+pub(crate) struct ReportIndex {
+    shards: RwLock<HashMap<Shard, Arc<Mutex<ShardIndex>>>>,
+}
+
+impl ReportIndex {
+    /// Return the index for `shard`, creating an empty one if necessary.
+    pub(crate) fn shard(&self, shard: Shard) -> Arc<Mutex<ShardIndex>> {
+        if let Some(index) = self.shards.read().unwrap().get(&shard) {
+            return index.clone();
+        }
+        self.shards
+            .write()
+            .unwrap()
+            .entry(shard)
+            .or_default()
+            .clone()
+    }
+
+    /// Whether any shard holds the report with this signature.
+    pub(crate) fn contains(&self, signature: &[u8; 64]) -> bool {
+        self.shards
+            .read()
+            .unwrap()
+            .values()
+            .any(|index| index.lock().unwrap().seen.contains_key(signature))
+    }
+
+    /// Forget every report accepted before `oldest`.
+    pub(crate) fn expire(&self, oldest: ReportTimestamp) {
+        for index in self.shards.read().unwrap().values() {
+            let mut index = index.lock().unwrap();
+            index.seen.retain(|_, timeframe| *timeframe >= oldest);
+            index
+                .published
+                .retain(|_, (_, timeframe)| *timeframe >= oldest);
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ShardIndex {
+    /// The batch each report was accepted into, keyed by its signature.
+    seen: HashMap<[u8; 64], ReportTimestamp>,
+    /// The highest TCK index published for each report verification key, and
+    /// the batch of the report that published it.
+    published: HashMap<[u8; 32], (u16, ReportTimestamp)>,
+}
+
+impl ShardIndex {
+    /// Return the batch `report` was accepted into, if it was already saved.
+    pub(crate) fn lookup(&self, report: &SignedReport) -> Option<ReportTimestamp> {
+        self.seen.get(&ReportFields::of(report).signature).copied()
+    }
+
+    /// Reject a report that re-reports TCK indices already published for its
+    /// verification key.
+    ///
+    /// Reports are signed by the client, so the server cannot trim an
+    /// overlapping report down to its new indices; the client must instead
+    /// submit a report whose `j_1` follows the last published `j_2`.
+    pub(crate) fn check_overlap(&self, report: &SignedReport) -> Result<(), ErrReport> {
+        let fields = ReportFields::of(report);
+        match self.published.get(&fields.rvk) {
+            Some(&(j_2, _)) if fields.j_1 <= j_2 => Err(eyre!(
+                "Report starts at TCK index {}, but indices up to {} were already published for this key",
+                fields.j_1,
+                j_2
+            ))
+            .set_status(StatusCode::CONFLICT),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record that `report` was accepted into the batch for `timeframe`.
+    pub(crate) fn insert(&mut self, report: &SignedReport, timeframe: ReportTimestamp) {
+        let fields = ReportFields::of(report);
+        self.seen.insert(fields.signature, timeframe);
+        let published = self
+            .published
+            .entry(fields.rvk)
+            .or_insert((fields.j_2, timeframe));
+        if fields.j_2 >= published.0 {
+            *published = (fields.j_2, timeframe);
+        }
+    }
+}
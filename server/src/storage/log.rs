@@ -0,0 +1,187 @@
+use super::{read_reports, ReportTimestamp};
+use crate::keys::{self, PublicKeyInfo};
+use crate::merkle::{self, Hash};
+use crate::receipt::report_hash;
+use crate::serde_hex::{all_as_hex, as_hex, option_as_hex};
+use serde::Serialize;
+use std::convert::TryInto;
+
+/// The size of an encoded [`LogEntry`].
+pub(crate) const ENTRY_LEN: usize = 8 + 4 + 32;
+
+/// A sealed batch, as committed to a shard's transparency log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct LogEntry {
+    pub(crate) timestamp: u64,
+    /// The number of reports in the batch.
+    pub(crate) count: u32,
+    /// The root hash of the Merkle tree of the batch's reports, in the order
+    /// they are published in.
+    #[serde(serialize_with = "as_hex")]
+    pub(crate) reports_root: Hash,
+}
+
+/// The leaf hash of each report in a sealed batch.
+pub(crate) fn report_leaves(batch: &[u8]) -> Vec<Hash> {
+    read_reports(batch)
+        .0
+        .iter()
+        .map(|report| {
+            let mut bytes = Vec::new();
+            report
+                .write(&mut bytes)
+                .expect("writing to a Vec cannot fail");
+            merkle::leaf_hash(&bytes)
+        })
+        .collect()
+}
+
+impl LogEntry {
+    pub(crate) fn of(timeframe: ReportTimestamp, batch: &[u8]) -> Self {
+        let leaves = report_leaves(batch);
+        Self {
+            timestamp: timeframe.0,
+            count: leaves.len() as u32,
+            reports_root: merkle::root(&leaves),
+        }
+    }
+
+    /// Encode the entry as the data of its leaf in the log:
+    ///
+    /// ```text
+    /// [timestamp: u64 LE][count: u32 LE][reports_root: 32]
+    /// ```
+    pub(crate) fn encode(&self) -> [u8; ENTRY_LEN] {
+        let mut bytes = [0; ENTRY_LEN];
+        bytes[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.count.to_le_bytes());
+        bytes[12..].copy_from_slice(&self.reports_root);
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8; ENTRY_LEN]) -> Self {
+        Self {
+            timestamp: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            count: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            reports_root: bytes[12..].try_into().unwrap(),
+        }
+    }
+
+    pub(crate) fn leaf_hash(&self) -> Hash {
+        merkle::leaf_hash(&self.encode())
+    }
+}
+
+/// A signed statement of the size and root hash of a shard's log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct TreeHead {
+    pub(crate) shard: u64,
+    pub(crate) tree_size: u64,
+    #[serde(serialize_with = "as_hex")]
+    pub(crate) root_hash: Hash,
+    /// When the tree head was signed, in seconds since the Unix epoch.
+    pub(crate) timestamp: u64,
+    #[serde(
+        serialize_with = "option_as_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) public_key: Option<[u8; 32]>,
+    #[serde(
+        serialize_with = "option_as_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) signature: Option<[u8; 64]>,
+}
+
+impl TreeHead {
+    /// The signed message:
+    ///
+    /// ```text
+    /// "tcn-tree-head-v1"[shard: u64 LE][tree_size: u64 LE][timestamp: u64 LE][root_hash: 32]
+    /// ```
+    pub(crate) fn message(&self) -> Vec<u8> {
+        let mut message = b"tcn-tree-head-v1".to_vec();
+        message.extend_from_slice(&self.shard.to_le_bytes());
+        message.extend_from_slice(&self.tree_size.to_le_bytes());
+        message.extend_from_slice(&self.timestamp.to_le_bytes());
+        message.extend_from_slice(&self.root_hash);
+        message
+    }
+
+    /// Sign the tree head with `sign`, leaving it unsigned if there is no key.
+    pub(crate) fn sign(
+        self,
+        sign: impl FnOnce(&[u8]) -> Option<(PublicKeyInfo, [u8; 64])>,
+    ) -> Self {
+        let (public_key, signature) = keys::signature(&self.message(), sign);
+        Self {
+            public_key,
+            signature,
+            ..self
+        }
+    }
+}
+
+/// A proof that the tree of the first `first` entries of a log is a prefix of
+/// the tree of its first `second` entries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ConsistencyProof {
+    pub(crate) first: u64,
+    pub(crate) second: u64,
+    #[serde(serialize_with = "all_as_hex")]
+    pub(crate) proof: Vec<Hash>,
+}
+
+/// A proof that a report is in a batch, and that the batch is in a shard's
+/// log of `tree_size` entries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct InclusionProof {
+    pub(crate) tree_size: u64,
+    /// The batch's entry in the log, and its index.
+    pub(crate) entry: LogEntry,
+    pub(crate) entry_index: u64,
+    /// The audit path from the entry's leaf to the log's root hash.
+    #[serde(serialize_with = "all_as_hex")]
+    pub(crate) entry_proof: Vec<Hash>,
+    /// The index of the report in the batch.
+    pub(crate) report_index: u64,
+    /// The audit path from the report's leaf to the entry's `reports_root`.
+    #[serde(serialize_with = "all_as_hex")]
+    pub(crate) report_proof: Vec<Hash>,
+}
+
+/// Find the report with the given SHA-256 hash in `batch`, returning its index
+/// and the audit path to the root of the batch's reports.
+pub(crate) fn prove_report(batch: &[u8], report_sha256: &Hash) -> Option<(usize, Vec<Hash>)> {
+    let (reports, _) = read_reports(batch);
+    let index = reports
+        .iter()
+        .position(|report| report_hash(report) == *report_sha256)?;
+    Some((index, merkle::inclusion_proof(index, &report_leaves(batch))))
+}
+
+#[test]
+fn test_log_entry() {
+    let mut batch = Vec::new();
+    let mut reports = Vec::new();
+    for _ in 0..3 {
+        let report = super::test_report();
+        report.write(&mut batch).unwrap();
+        reports.push(report);
+    }
+    let entry = LogEntry::of(ReportTimestamp(9), &batch);
+    assert_eq!((entry.timestamp, entry.count), (9, 3));
+    assert_eq!(LogEntry::decode(&entry.encode()), entry);
+
+    let (index, proof) = prove_report(&batch, &report_hash(&reports[2])).unwrap();
+    assert_eq!(index, 2);
+    let leaves = report_leaves(&batch);
+    assert!(merkle::verify_inclusion(
+        index,
+        3,
+        leaves[2],
+        &proof,
+        entry.reports_root
+    ));
+    assert!(prove_report(&batch, &[0; 32]).is_none());
+}
@@ -0,0 +1,134 @@
+use super::{read_reports, ReportTimestamp, Shard};
+use crate::keys::{self, PublicKeyInfo};
+use crate::serde_hex::{as_hex, option_as_hex};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// A description of a sealed batch, for clients planning their downloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct BatchInfo {
+    pub(crate) timestamp: u64,
+    /// The number of reports in the batch.
+    pub(crate) count: u32,
+    /// The size of the batch, in bytes.
+    pub(crate) size: u64,
+    /// The SHA-256 hash of the batch.
+    #[serde(serialize_with = "as_hex")]
+    pub(crate) sha256: [u8; 32],
+    #[serde(
+        serialize_with = "option_as_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) public_key: Option<[u8; 32]>,
+    /// The signature of [`BatchInfo::message`], if the server has a signing
+    /// key.
+    #[serde(
+        serialize_with = "option_as_hex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) signature: Option<[u8; 64]>,
+}
+
+/// The size of a [`BatchInfo`] in the binary manifest.
+const ENTRY_LEN: usize = 8 + 4 + 8 + 32;
+
+impl BatchInfo {
+    pub(crate) fn of(timeframe: ReportTimestamp, batch: &[u8]) -> Self {
+        let mut sha256 = [0; 32];
+        sha256.copy_from_slice(&Sha256::digest(batch));
+        Self {
+            timestamp: timeframe.0,
+            count: read_reports(batch).0.len() as u32,
+            size: batch.len() as u64,
+            sha256,
+            public_key: None,
+            signature: None,
+        }
+    }
+
+    /// The signed message, for the batch in `shard`:
+    ///
+    /// ```text
+    /// "tcn-batch-v1"[shard: u64 LE][timestamp: u64 LE][count: u32 LE][size: u64 LE][sha256: 32]
+    /// ```
+    pub(crate) fn message(&self, shard: Shard) -> Vec<u8> {
+        let mut message = b"tcn-batch-v1".to_vec();
+        message.extend_from_slice(&shard.0.to_le_bytes());
+        message.extend_from_slice(&self.timestamp.to_le_bytes());
+        message.extend_from_slice(&self.count.to_le_bytes());
+        message.extend_from_slice(&self.size.to_le_bytes());
+        message.extend_from_slice(&self.sha256);
+        message
+    }
+
+    /// Sign the description of the batch in `shard` with `sign`, leaving it
+    /// unsigned if there is no key.
+    pub(crate) fn sign(
+        self,
+        shard: Shard,
+        sign: impl FnOnce(&[u8]) -> Option<(PublicKeyInfo, [u8; 64])>,
+    ) -> Self {
+        let (public_key, signature) = keys::signature(&self.message(shard), sign);
+        Self {
+            public_key,
+            signature,
+            ..self
+        }
+    }
+}
+
+/// Encode a manifest in its compact binary form, a `u32 LE` count followed by
+/// an entry for each batch:
+///
+/// ```text
+/// [timestamp: u64 LE][count: u32 LE][size: u64 LE][sha256: 32]
+/// ```
+pub(crate) fn encode_binary(batches: &[BatchInfo]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + ENTRY_LEN * batches.len());
+    bytes.extend_from_slice(&(batches.len() as u32).to_le_bytes());
+    for batch in batches {
+        bytes.extend_from_slice(&batch.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&batch.count.to_le_bytes());
+        bytes.extend_from_slice(&batch.size.to_le_bytes());
+        bytes.extend_from_slice(&batch.sha256);
+    }
+    bytes
+}
+
+/// The message signed for a manifest of `shard` served as `body`, in either
+/// form:
+///
+/// ```text
+/// "tcn-manifest-v1"[shard: u64 LE][body]
+/// ```
+pub(crate) fn message(shard: Shard, body: &[u8]) -> Vec<u8> {
+    let mut message = b"tcn-manifest-v1".to_vec();
+    message.extend_from_slice(&shard.0.to_le_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+#[test]
+fn test_manifest_encoding() {
+    let mut batch = Vec::new();
+    for _ in 0..2 {
+        super::test_report().write(&mut batch).unwrap();
+    }
+    let info = BatchInfo::of(ReportTimestamp(9), &batch);
+    assert_eq!((info.timestamp, info.count, info.size), (9, 2, 268));
+
+    let bytes = encode_binary(&[info, info]);
+    assert_eq!(bytes.len(), 4 + 2 * ENTRY_LEN);
+    assert_eq!(&bytes[..4], &2u32.to_le_bytes());
+    assert_eq!(&bytes[4 + ENTRY_LEN - 32..4 + ENTRY_LEN], &info.sha256);
+
+    let json = serde_json::to_value(info).unwrap();
+    assert_eq!(json["sha256"], hex::encode(info.sha256));
+    assert_eq!(json["count"], 2);
+    assert!(json.get("signature").is_none());
+
+    // Manifest signatures cannot be passed off as signatures of the same
+    // bytes in another shard or context.
+    assert_ne!(message(Shard(1), &bytes), message(Shard(2), &bytes));
+    assert!(message(Shard(1), &bytes).starts_with(b"tcn-manifest-v1"));
+}
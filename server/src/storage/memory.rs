@@ -0,0 +1,439 @@
+use super::wal::{Record, Wal};
+use super::{
+    read_reports, Encoded, ErrReport, LogEntry, ReportStore, ReportTimestamp, Shard, SignedReport,
+    StorageEntry,
+};
+use crate::auth::{CodeId, CodeRecord};
+use crate::error::context::Status;
+use bytes::Bytes;
+use eyre::eyre;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::{debug, warn};
+use warp::http::StatusCode;
+
+/// The batches of a single shard.
+///
+/// Each batch has its own lock, so that appending to the current batch never
+/// blocks reads of sealed batches; the map itself is only write-locked to add
+/// or expire batches.
+type ShardEntries = RwLock<BTreeMap<ReportTimestamp, RwLock<StorageEntry>>>;
+
+/// Keeps every batch in memory.
+///
+/// Without a write-ahead log, all reports are lost on restart.
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    shards: RwLock<HashMap<Shard, Arc<ShardEntries>>>,
+    codes: Mutex<HashMap<CodeId, CodeRecord>>,
+    logs: Mutex<HashMap<Shard, Vec<LogEntry>>>,
+    /// The sorted TCNs of sealed batches.  Other forms of sealed batches are
+    /// cached by [`Storage`](super::Storage), so they are not kept here.
+    tcns: RwLock<HashMap<(Shard, ReportTimestamp), Bytes>>,
+    wal: Option<Wal>,
+}
+
+impl MemoryStore {
+    /// Create a store whose batches, codes and transparency logs are logged to
+    /// `path`, rebuilding them from any records already in the log.
+    ///
+    /// Sealed batches are restored exactly as they were first sealed, so that
+    /// they are served unchanged across restarts.
+    pub(crate) fn with_wal(path: &Path) -> Result<Self, ErrReport> {
+        let (wal, records) = Wal::open(path)?;
+        let store = Self::default();
+        for record in records {
+            match record {
+                Record::Report(shard, timeframe, report) => {
+                    store.append(shard, timeframe, &[report], |_| Ok(()))?
+                }
+                Record::Sealed(shard, timeframe, bytes) => {
+                    store
+                        .shard_or_default(shard)
+                        .write()
+                        .unwrap()
+                        .insert(timeframe, RwLock::new(StorageEntry::Sealed(bytes)));
+                }
+                Record::Code(id, record) => store.put_code(id, &record)?,
+                Record::Log(shard, entry) => store.append_log(shard, &entry)?,
+            }
+        }
+        Ok(Self {
+            wal: Some(wal),
+            ..store
+        })
+    }
+
+    /// Return the batches of `shard`, if it has any.
+    fn shard(&self, shard: Shard) -> Option<Arc<ShardEntries>> {
+        self.shards.read().unwrap().get(&shard).cloned()
+    }
+
+    /// Return the batches of `shard`, creating an empty set if necessary.
+    fn shard_or_default(&self, shard: Shard) -> Arc<ShardEntries> {
+        if let Some(entries) = self.shard(shard) {
+            return entries;
+        }
+        self.shards
+            .write()
+            .unwrap()
+            .entry(shard)
+            .or_default()
+            .clone()
+    }
+
+    /// Return the timeframes of the sealed or open batches of `shard`.
+    fn timeframes(&self, shard: Shard, sealed: bool) -> Vec<ReportTimestamp> {
+        let entries = match self.shard(shard) {
+            Some(entries) => entries,
+            None => return Vec::new(),
+        };
+        let entries = entries.read().unwrap();
+        entries
+            .iter()
+            .filter(|(_, entry)| {
+                matches!(*entry.read().unwrap(), StorageEntry::Sealed(_)) == sealed
+            })
+            .map(|(timeframe, _)| *timeframe)
+            .collect()
+    }
+
+    /// Append `reports` to an open batch, calling `log` first while the batch
+    /// is locked.
+    fn append(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        new_reports: &[SignedReport],
+        log: impl FnOnce(&[SignedReport]) -> Result<(), ErrReport>,
+    ) -> Result<(), ErrReport> {
+        let entries = self.shard_or_default(shard);
+        loop {
+            if let Some(entry) = entries.read().unwrap().get(&timeframe) {
+                let mut entry = entry.write().unwrap();
+                return match *entry {
+                    StorageEntry::Open(ref mut reports) => {
+                        log(new_reports)?;
+                        reports.extend_from_slice(new_reports);
+                        Ok(())
+                    }
+                    StorageEntry::Sealed(_) => {
+                        Err(eyre!("Current entry is already sealed. Is time broken?"))
+                            .set_status(StatusCode::CONFLICT)?
+                    }
+                };
+            }
+            entries.write().unwrap().entry(timeframe).or_default();
+        }
+    }
+}
+
+impl ReportStore for MemoryStore {
+    fn save(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        reports: &[SignedReport],
+    ) -> Result<(), ErrReport> {
+        self.append(shard, timeframe, reports, |reports| match self.wal {
+            Some(ref wal) => {
+                let records: Vec<_> = reports
+                    .iter()
+                    .map(|report| Record::Report(shard, timeframe, report.clone()))
+                    .collect();
+                wal.append(&records)
+            }
+            None => Ok(()),
+        })
+    }
+
+    fn seal(&self, current: ReportTimestamp) -> Result<Vec<(Shard, ReportTimestamp)>, ErrReport> {
+        let mut sealed = Vec::new();
+        let shards: Vec<_> = self
+            .shards
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(shard, entries)| (*shard, entries.clone()))
+            .collect();
+        for (shard, entries) in shards {
+            for (timeframe, entry) in entries.read().unwrap().range(..current) {
+                let log = |bytes: &Bytes| match self.wal {
+                    Some(ref wal) => {
+                        wal.append(&[Record::Sealed(shard, *timeframe, bytes.clone())])
+                    }
+                    None => Ok(()),
+                };
+                match entry.write().unwrap().seal(log) {
+                    Ok(true) => sealed.push((shard, *timeframe)),
+                    Ok(false) => {}
+                    // The batch stays open, and is sealed on the next attempt.
+                    Err(error) => warn!(?error, ?shard, ?timeframe, "failed to seal batch"),
+                }
+            }
+        }
+        // The sealed records now hold the batches' reports, so the report
+        // records can be compacted away.  The batches are already sealed, so
+        // a failure only leaves the log larger until the next compaction.
+        if let (Some(ref wal), false) = (&self.wal, sealed.is_empty()) {
+            if let Err(error) = wal.retain(|_| true) {
+                warn!(?error, "failed to compact write-ahead log");
+            }
+        }
+        Ok(sealed)
+    }
+
+    fn expire(&self, oldest: ReportTimestamp) -> Result<(), ErrReport> {
+        let mut expired = 0;
+        let shards: Vec<_> = self.shards.read().unwrap().values().cloned().collect();
+        for entries in shards {
+            let mut entries = entries.write().unwrap();
+            let before = entries.len();
+            *entries = entries.split_off(&oldest);
+            expired += before - entries.len();
+        }
+        // Drop the maps of shards left without batches.  A map that is still
+        // referenced may be about to receive a report, so it is kept until a
+        // later expiry.
+        self.shards.write().unwrap().retain(|_, entries| {
+            Arc::strong_count(entries) > 1 || !entries.read().unwrap().is_empty()
+        });
+        self.tcns
+            .write()
+            .unwrap()
+            .retain(|(_, timeframe), _| *timeframe >= oldest);
+
+        if expired > 0 {
+            debug!(expired, ?oldest, "expired report batches");
+            if let Some(ref wal) = self.wal {
+                wal.retain(|record| match *record {
+                    Record::Report(_, timeframe, _) | Record::Sealed(_, timeframe, _) => {
+                        timeframe >= oldest
+                    }
+                    Record::Code(..) | Record::Log(..) => true,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each_report(
+        &self,
+        visit: &mut dyn FnMut(Shard, ReportTimestamp, SignedReport),
+    ) -> Result<(), ErrReport> {
+        let shards: Vec<_> = self
+            .shards
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(shard, entries)| (*shard, entries.clone()))
+            .collect();
+        for (shard, entries) in shards {
+            for (timeframe, entry) in entries.read().unwrap().iter() {
+                match *entry.read().unwrap() {
+                    StorageEntry::Open(ref reports) => {
+                        for report in reports {
+                            visit(shard, *timeframe, report.clone());
+                        }
+                    }
+                    StorageEntry::Sealed(ref bytes) => {
+                        for report in read_reports(bytes).0 {
+                            visit(shard, *timeframe, report);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn put_code(&self, id: CodeId, record: &CodeRecord) -> Result<(), ErrReport> {
+        let mut codes = self.codes.lock().unwrap();
+        if codes
+            .get(&id)
+            .map_or(false, |earlier| !record.replaces(earlier))
+        {
+            return Ok(());
+        }
+        if let Some(ref wal) = self.wal {
+            wal.append(&[Record::Code(id, record.clone())])?;
+        }
+        codes.insert(id, record.clone());
+        Ok(())
+    }
+
+    fn codes(&self) -> Result<Vec<(CodeId, CodeRecord)>, ErrReport> {
+        Ok(self
+            .codes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, record)| (*id, record.clone()))
+            .collect())
+    }
+
+    fn append_log(&self, shard: Shard, entry: &LogEntry) -> Result<(), ErrReport> {
+        let mut logs = self.logs.lock().unwrap();
+        if let Some(ref wal) = self.wal {
+            wal.append(&[Record::Log(shard, *entry)])?;
+        }
+        logs.entry(shard).or_default().push(*entry);
+        Ok(())
+    }
+
+    fn log(&self, shard: Shard) -> Result<Vec<LogEntry>, ErrReport> {
+        Ok(self
+            .logs
+            .lock()
+            .unwrap()
+            .get(&shard)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn shards(&self) -> Result<Vec<Shard>, ErrReport> {
+        Ok(self.shards.read().unwrap().keys().copied().collect())
+    }
+
+    fn sealed(&self, shard: Shard) -> Result<Vec<ReportTimestamp>, ErrReport> {
+        Ok(self.timeframes(shard, true))
+    }
+
+    fn open(&self, shard: Shard) -> Result<Vec<ReportTimestamp>, ErrReport> {
+        Ok(self.timeframes(shard, false))
+    }
+
+    // Compressed forms are already kept in memory by `Storage`, and are
+    // recomputed after a restart rather than logged.
+    fn save_encoded(
+        &self,
+        _shard: Shard,
+        _timeframe: ReportTimestamp,
+        _encoded: &Encoded,
+    ) -> Result<(), ErrReport> {
+        Ok(())
+    }
+
+    fn encoded(
+        &self,
+        _shard: Shard,
+        _timeframe: ReportTimestamp,
+    ) -> Result<Option<Encoded>, ErrReport> {
+        Ok(None)
+    }
+
+    fn save_filter(
+        &self,
+        _shard: Shard,
+        _timeframe: ReportTimestamp,
+        _filter: &[u8],
+    ) -> Result<(), ErrReport> {
+        Ok(())
+    }
+
+    fn filter(
+        &self,
+        _shard: Shard,
+        _timeframe: ReportTimestamp,
+    ) -> Result<Option<Bytes>, ErrReport> {
+        Ok(None)
+    }
+
+    fn save_tcns(
+        &self,
+        shard: Shard,
+        timeframe: ReportTimestamp,
+        tcns: &[u8],
+    ) -> Result<(), ErrReport> {
+        self.tcns
+            .write()
+            .unwrap()
+            .insert((shard, timeframe), Bytes::copy_from_slice(tcns));
+        Ok(())
+    }
+
+    fn has_tcns(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<bool, ErrReport> {
+        Ok(self.tcns.read().unwrap().contains_key(&(shard, timeframe)))
+    }
+
+    fn tcns(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<Option<Bytes>, ErrReport> {
+        Ok(self.tcns.read().unwrap().get(&(shard, timeframe)).cloned())
+    }
+
+    fn get(&self, shard: Shard, timeframe: ReportTimestamp) -> Result<Bytes, ErrReport> {
+        let entries = self
+            .shard(shard)
+            .ok_or(eyre!("No entries for this shard"))
+            .set_status(StatusCode::NOT_FOUND)?;
+        let entries = entries.read().unwrap();
+        let entry = entries
+            .get(&timeframe)
+            .ok_or(eyre!("No entries for this timeframe"))
+            .set_status(StatusCode::NOT_FOUND)?
+            .read()
+            .unwrap();
+
+        match *entry {
+            StorageEntry::Sealed(ref bytes) => Ok(bytes.clone()),
+            StorageEntry::Open(_) => Err(eyre!("Report batch has not been sealed yet"))
+                .set_status(StatusCode::SERVICE_UNAVAILABLE)?,
+        }
+    }
+}
+
+#[test]
+fn test_memory_store_lifecycle() {
+    let store = MemoryStore::default();
+    let report = super::test_report();
+    store
+        .save(Shard(1), ReportTimestamp(5), std::slice::from_ref(&report))
+        .unwrap();
+    store.save(Shard(2), ReportTimestamp(6), &[report]).unwrap();
+
+    let status = |result: Result<_, ErrReport>| result.err().map(|e| e.0.context().status);
+    assert_eq!(
+        status(store.get(Shard(1), ReportTimestamp(5))),
+        Some(StatusCode::SERVICE_UNAVAILABLE)
+    );
+
+    store.seal(ReportTimestamp(6)).unwrap();
+    assert_eq!(store.get(Shard(1), ReportTimestamp(5)).unwrap().len(), 134);
+    assert_eq!(
+        status(store.get(Shard(2), ReportTimestamp(6))),
+        Some(StatusCode::SERVICE_UNAVAILABLE)
+    );
+
+    store.expire(ReportTimestamp(6)).unwrap();
+    assert_eq!(
+        status(store.get(Shard(1), ReportTimestamp(5))),
+        Some(StatusCode::NOT_FOUND)
+    );
+    assert_eq!(store.shards().unwrap(), vec![Shard(2)]);
+}
+
+#[test]
+fn test_memory_store_restores_sealed_batches() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("wal");
+    let reports: Vec<_> = (0..16).map(|_| super::test_report()).collect();
+
+    let store = MemoryStore::with_wal(&path).unwrap();
+    store.save(Shard(1), ReportTimestamp(5), &reports).unwrap();
+    store.save(Shard(1), ReportTimestamp(6), &reports).unwrap();
+    store.seal(ReportTimestamp(6)).unwrap();
+    let sealed = store.get(Shard(1), ReportTimestamp(5)).unwrap();
+    drop(store);
+    // Sealing compacts the reports of the sealed batch out of the log.
+    let (_, records) = Wal::open(&path).unwrap();
+    assert_eq!(records.len(), reports.len() + 1);
+    assert!(!records
+        .iter()
+        .any(|record| matches!(record, Record::Report(_, ReportTimestamp(5), _))));
+
+    let store = MemoryStore::with_wal(&path).unwrap();
+    assert_eq!(store.get(Shard(1), ReportTimestamp(5)).unwrap(), sealed);
+    assert_eq!(store.sealed(Shard(1)).unwrap(), vec![ReportTimestamp(5)]);
+    assert!(store.seal(ReportTimestamp(6)).unwrap().is_empty());
+    assert_eq!(store.seal(ReportTimestamp(7)).unwrap().len(), 1);
+}
@@ -0,0 +1,15 @@
+/// Encode TCNs as their sorted, deduplicated concatenation, so that a client
+/// can intersect them with its own sorted TCNs in a single pass.
+pub(crate) fn encode_sorted(mut tcns: Vec<[u8; 16]>) -> Vec<u8> {
+    tcns.sort_unstable();
+    tcns.dedup();
+    tcns.concat()
+}
+
+#[test]
+fn test_encode_sorted() {
+    let encoded = encode_sorted(vec![[3; 16], [1; 16], [2; 16], [1; 16]]);
+    assert_eq!(encoded.len(), 3 * 16);
+    assert_eq!(encoded, [[1; 16], [2; 16], [3; 16]].concat());
+    assert!(encode_sorted(Vec::new()).is_empty());
+}
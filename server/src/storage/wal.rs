@@ -0,0 +1,371 @@
+use super::{ErrReport, LogEntry, ReportTimestamp, Shard, SignedReport};
+use crate::auth::{CodeId, CodeRecord};
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// Size of the record header: payload length and CRC32 of the payload.
+const HEADER_LEN: usize = 8;
+/// Size of the shard and timeframe that prefix the body of batch records.
+const KEY_LEN: usize = 16;
+
+const REPORT: u8 = 0;
+const SEALED: u8 = 1;
+const CODE: u8 = 2;
+const LOG: u8 = 3;
+
+/// A change to the state of a memory store.
+#[derive(Debug, Clone)]
+pub(crate) enum Record {
+    /// A report was accepted into the open batch for the timeframe.
+    Report(Shard, ReportTimestamp, SignedReport),
+    /// The batch for the timeframe was sealed into these bytes, which replace
+    /// the reports recorded for it.
+    Sealed(Shard, ReportTimestamp, Bytes),
+    /// The state of an authorization code changed.
+    Code(CodeId, CodeRecord),
+    /// An entry was appended to the transparency log of the shard.
+    Log(Shard, LogEntry),
+}
+
+/// A write-ahead log of accepted reports, sealed batches, authorization
+/// codes and transparency log entries.
+///
+/// Each record is laid out as
+///
+/// ```text
+/// [payload length: u32 LE][crc32(payload): u32 LE][kind: u8][body]
+/// ```
+///
+/// where the payload is everything after the header.  The body of a report
+/// (kind 0) or sealed batch (kind 1) is `[shard: u64 LE][timeframe: u64 LE]`
+/// followed by the serialized report or batch, and the body of a code
+/// (kind 2) is
+///
+/// ```text
+/// [id: 32][flags: u8][expires_at: u64 LE][count: u32 LE][signatures: 64 * count]
+/// ```
+///
+/// where bits 0 to 3 of the flags mark that the code expires, was consumed, is
+/// pending and was revoked.  The body of a transparency log entry (kind 3) is
+/// `[shard: u64 LE]` followed by the entry's encoding.  A record is only
+/// acknowledged once it has been synced to disk, so on replay any record that
+/// is incomplete or fails its checksum must be part of a torn write at the
+/// tail of the log, and everything from that point on is truncated.
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Wal {
+    /// Open the log at `path`, returning it along with every intact record.
+    pub(crate) fn open(path: &Path) -> Result<(Self, Vec<Record>), ErrReport> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| ErrReport::from(e).wrap_err("Could not open write-ahead log"))?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (records, valid_len) = parse_records(&bytes);
+        if valid_len < bytes.len() {
+            warn!(
+                valid_len,
+                discarded = bytes.len() - valid_len,
+                "truncating corrupt tail of write-ahead log"
+            );
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(valid_len as u64))?;
+        info!(count = records.len(), "replayed write-ahead log");
+
+        Ok((
+            Self {
+                path: path.to_owned(),
+                file: Mutex::new(file),
+            },
+            records,
+        ))
+    }
+
+    /// Durably append records to the log, all or none.
+    pub(crate) fn append(&self, records: &[Record]) -> Result<(), ErrReport> {
+        let records: Vec<u8> = records.iter().flat_map(encode_record).collect();
+        let mut file = self.file.lock().unwrap();
+        let start = file.seek(SeekFrom::Current(0))?;
+        if let Err(e) = file.write_all(&records).and_then(|_| file.sync_data()) {
+            // Drop any records that were written, so that later appends are
+            // not hidden behind a partial one on replay.
+            file.set_len(start)?;
+            file.seek(SeekFrom::Start(start))?;
+            return Err(ErrReport::from(e).wrap_err("Could not append to write-ahead log"));
+        }
+        Ok(())
+    }
+
+    /// Rewrite the log, keeping only the records for which `keep` returns true
+    /// and that have not been superseded: the reports of a sealed batch are
+    /// dropped, since its sealed record holds them, and only the current state
+    /// of each code is kept.  Transparency log entries are never superseded.
+    ///
+    /// The new log is written alongside the old one and renamed over it, so a
+    /// crash during compaction leaves one of the two intact.
+    pub(crate) fn retain(&self, keep: impl Fn(&Record) -> bool) -> Result<(), ErrReport> {
+        let mut file = self.file.lock().unwrap();
+        let bytes = fs::read(&self.path)?;
+        let (records, _) = parse_records(&bytes);
+        let before = records.len();
+        let sealed: HashSet<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Sealed(shard, timeframe, _) => Some((*shard, *timeframe)),
+                _ => None,
+            })
+            .collect();
+        let mut codes: HashMap<CodeId, (usize, &CodeRecord)> = HashMap::new();
+        for (i, record) in records.iter().enumerate() {
+            if let Record::Code(id, record) = record {
+                if codes
+                    .get(id)
+                    .map_or(true, |(_, earlier)| record.replaces(earlier))
+                {
+                    codes.insert(*id, (i, record));
+                }
+            }
+        }
+        let codes: HashSet<_> = codes.values().map(|(i, _)| *i).collect();
+
+        let tmp = self.path.with_extension("compact");
+        let mut compacted = File::create(&tmp)?;
+        let mut kept = 0;
+        for (i, record) in records.iter().enumerate() {
+            let superseded = match *record {
+                Record::Report(shard, timeframe, _) => sealed.contains(&(shard, timeframe)),
+                Record::Sealed(..) | Record::Log(..) => false,
+                Record::Code(..) => !codes.contains(&i),
+            };
+            if keep(record) && !superseded {
+                compacted.write_all(&encode_record(record))?;
+                kept += 1;
+            }
+        }
+        compacted.sync_all()?;
+        fs::rename(&tmp, &self.path)
+            .map_err(|e| ErrReport::from(e).wrap_err("Could not compact write-ahead log"))?;
+
+        compacted.seek(SeekFrom::End(0))?;
+        *file = compacted;
+        debug!(before, kept, "compacted write-ahead log");
+        Ok(())
+    }
+}
+
+/// Encode a record, including its header.
+fn encode_record(record: &Record) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + KEY_LEN + 256);
+    match record {
+        Record::Report(shard, timeframe, report) => {
+            payload.push(REPORT);
+            payload.extend_from_slice(&shard.0.to_le_bytes());
+            payload.extend_from_slice(&timeframe.0.to_le_bytes());
+            report
+                .write(&mut payload)
+                .expect("report serialization should be infallible");
+        }
+        Record::Sealed(shard, timeframe, batch) => {
+            payload.push(SEALED);
+            payload.extend_from_slice(&shard.0.to_le_bytes());
+            payload.extend_from_slice(&timeframe.0.to_le_bytes());
+            payload.extend_from_slice(batch);
+        }
+        Record::Code(id, record) => {
+            let signatures = record.consumed_by.as_deref().unwrap_or_default();
+            let flags = record.expires_at.is_some() as u8
+                | (record.consumed_by.is_some() as u8) << 1
+                | (record.pending as u8) << 2
+                | (record.revoked as u8) << 3;
+            payload.push(CODE);
+            payload.extend_from_slice(id);
+            payload.push(flags);
+            payload.extend_from_slice(&record.expires_at.unwrap_or(0).to_le_bytes());
+            payload.extend_from_slice(&(signatures.len() as u32).to_le_bytes());
+            for signature in signatures {
+                payload.extend_from_slice(signature);
+            }
+        }
+        Record::Log(shard, entry) => {
+            payload.push(LOG);
+            payload.extend_from_slice(&shard.0.to_le_bytes());
+            payload.extend_from_slice(&entry.encode());
+        }
+    }
+
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+/// Parse records from the start of `bytes`, returning them along with the
+/// length of the intact prefix.
+fn parse_records(bytes: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = bytes.get(offset..offset + HEADER_LEN) {
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let payload = match bytes.get(offset + HEADER_LEN..offset + HEADER_LEN + len) {
+            Some(payload) if len >= 1 && crc32fast::hash(payload) == crc => payload,
+            _ => break,
+        };
+        let record = match parse_payload(payload[0], &payload[1..]) {
+            Some(record) => record,
+            None => break,
+        };
+        records.push(record);
+        offset += HEADER_LEN + len;
+    }
+    (records, offset)
+}
+
+/// Parse the body of a record of the given kind.
+fn parse_payload(kind: u8, body: &[u8]) -> Option<Record> {
+    let u64_at = |offset: usize| -> Option<u64> {
+        Some(u64::from_le_bytes(
+            body.get(offset..offset + 8)?.try_into().unwrap(),
+        ))
+    };
+    match kind {
+        REPORT | SEALED => {
+            let shard = Shard(u64_at(0)?);
+            let timeframe = ReportTimestamp(u64_at(8)?);
+            let batch = &body[KEY_LEN..];
+            if kind == REPORT {
+                let report = SignedReport::read(batch).ok()?;
+                Some(Record::Report(shard, timeframe, report))
+            } else {
+                Some(Record::Sealed(
+                    shard,
+                    timeframe,
+                    Bytes::copy_from_slice(batch),
+                ))
+            }
+        }
+        CODE => {
+            let id: CodeId = body.get(..32)?.try_into().unwrap();
+            let flags = *body.get(32)?;
+            let expires_at = u64_at(33)?;
+            let count = u32::from_le_bytes(body.get(41..45)?.try_into().unwrap()) as usize;
+            let signatures = body
+                .get(45..)?
+                .chunks_exact(64)
+                .map(|signature| signature.try_into().unwrap())
+                .collect::<Vec<[u8; 64]>>();
+            if signatures.len() != count {
+                return None;
+            }
+            let record = CodeRecord {
+                expires_at: Some(expires_at).filter(|_| flags & 1 != 0),
+                consumed_by: Some(signatures).filter(|_| flags & 2 != 0),
+                pending: flags & 4 != 0,
+                revoked: flags & 8 != 0,
+            };
+            Some(Record::Code(id, record))
+        }
+        LOG => {
+            let shard = Shard(u64_at(0)?);
+            let entry = body.get(8..)?.try_into().ok()?;
+            Some(Record::Log(shard, LogEntry::decode(entry)))
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn test_wal_truncates_torn_tail() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("wal");
+    let report = super::test_report();
+    let record =
+        |shard, timeframe| Record::Report(Shard(shard), ReportTimestamp(timeframe), report.clone());
+    let keys = |records: &[Record]| -> Vec<_> {
+        records
+            .iter()
+            .map(|record| match *record {
+                Record::Report(shard, timeframe, _) => (shard.0, timeframe.0, REPORT),
+                Record::Sealed(shard, timeframe, _) => (shard.0, timeframe.0, SEALED),
+                Record::Code(..) => (0, 0, CODE),
+                Record::Log(shard, entry) => (shard.0, entry.timestamp, LOG),
+            })
+            .collect()
+    };
+
+    let (wal, records) = Wal::open(&path).unwrap();
+    assert!(records.is_empty());
+    wal.append(&[record(1, 2)]).unwrap();
+    wal.append(&[record(3, 4)]).unwrap();
+    drop(wal);
+
+    // Simulate a crash halfway through writing a third record.
+    let len = std::fs::metadata(&path).unwrap().len();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0xff; 20]).unwrap();
+    drop(file);
+
+    let (wal, records) = Wal::open(&path).unwrap();
+    assert_eq!(keys(&records), vec![(1, 2, REPORT), (3, 4, REPORT)]);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+    // New records go after the intact prefix, and compaction drops the
+    // reports of sealed batches and codes that were replaced.
+    let code = |revoked| {
+        Record::Code(
+            [1; 32],
+            CodeRecord {
+                expires_at: Some(10),
+                consumed_by: Some(vec![[2; 64]]),
+                revoked,
+                ..CodeRecord::default()
+            },
+        )
+    };
+    wal.append(&[code(false), code(true), code(false)]).unwrap();
+    wal.append(&[record(5, 6), record(5, 7)]).unwrap();
+    let batch = Bytes::from_static(b"sealed batch");
+    wal.append(&[Record::Sealed(Shard(5), ReportTimestamp(6), batch.clone())])
+        .unwrap();
+    let entry = LogEntry::of(ReportTimestamp(6), &batch);
+    wal.append(&[Record::Log(Shard(5), entry)]).unwrap();
+    wal.retain(|record| !matches!(record, Record::Report(Shard(3), ..)))
+        .unwrap();
+    wal.append(&[record(7, 8)]).unwrap();
+    drop(wal);
+    let records = Wal::open(&path).unwrap().1;
+    assert_eq!(
+        keys(&records),
+        vec![
+            (1, 2, REPORT),
+            (0, 0, CODE),
+            (5, 7, REPORT),
+            (5, 6, SEALED),
+            (5, 6, LOG),
+            (7, 8, REPORT)
+        ]
+    );
+    assert!(matches!(records[4], Record::Log(_, logged) if logged == entry));
+    assert!(matches!(
+        records[1],
+        Record::Code(_, ref record) if record.revoked && record.consumed_by == Some(vec![[2; 64]])
+    ));
+    assert!(matches!(records[3], Record::Sealed(_, _, ref bytes) if *bytes == batch));
+}
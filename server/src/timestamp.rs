@@ -11,6 +11,27 @@ impl std::str::FromStr for ReportTimestamp {
     }
 }
 
+/// A half-open range of timeframes, written `{from}..{to}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampRange {
+    pub from: ReportTimestamp,
+    pub to: ReportTimestamp,
+}
+
+impl std::str::FromStr for TimestampRange {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (from, to) = input
+            .split_once("..")
+            .ok_or("timestamp range must be `{from}..{to}`")?;
+        Ok(Self {
+            from: from.parse()?,
+            to: to.parse()?,
+        })
+    }
+}
+
 use super::OPTIONS;
 
 impl ReportTimestamp {
@@ -50,3 +71,12 @@ fn test_timestamp() {
     assert!(ReportTimestamp::from_time(ts.start_time()).unwrap() == ts);
     assert!(ReportTimestamp::from_time(ts.end_time()).unwrap() == ts);
 }
+
+#[test]
+fn test_timestamp_range() {
+    let range: TimestampRange = "3..7".parse().unwrap();
+    assert_eq!(range.from, ReportTimestamp(3));
+    assert_eq!(range.to, ReportTimestamp(7));
+    assert!("3".parse::<TimestampRange>().is_err());
+    assert!("3..x".parse::<TimestampRange>().is_err());
+}
@@ -21,10 +21,11 @@ use crate::shard::Shard;
 use crate::shard::ShardId;
 
 pub struct User {
-    rak: ReportAuthorizationKey, // Current rak
-    rak_shards: Vec<ShardId>,    // Shards this rak was used in
-    shard: Shard,                // Current shard
-    shard_hist: Vec<ShardId>,    // Shards since last report fetch
+    rak: ReportAuthorizationKey,   // Current rak
+    rak_shards: Vec<ShardId>,      // Shards this rak was used in
+    shard: Shard,                  // Current shard
+    shard_hist: Vec<ShardId>,      // Shards since last report fetch
+    unsealed: Vec<(ShardId, u64)>, // Batches to fetch again once sealed
     raks: Vec<(ReportAuthorizationKey, Vec<ShardId>)>,
     tck: TemporaryContactKey,
     observed_tcns: BTreeSet<TemporaryContactNumber>,
@@ -40,6 +41,7 @@ impl User {
             raks: Vec::new(),
             shard: Shard::init(shard_id, tx),
             shard_hist: vec![shard_id],
+            unsealed: Vec::new(),
             tck,
             observed_tcns: BTreeSet::default(),
         }
@@ -179,13 +181,21 @@ impl User {
             .as_secs()
             / OPTIONS.server_batch_interval;
 
-        for shard_id in self.shard_hist.iter() {
+        // Fetch the previous batch, and any earlier ones that were not sealed
+        // yet when they were last fetched.
+        let mut batches = std::mem::take(&mut self.unsealed);
+        batches.extend(
+            self.shard_hist
+                .iter()
+                .map(|shard_id| (*shard_id, batch_index - 1)),
+        );
+
+        for (shard_id, batch) in batches {
             let report_url = reqwest::Url::parse(&OPTIONS.server)?
                 // set shard_id as root
                 .join(&(shard_id.to_string() + "/"))?
                 .join("get_reports/")?
-                // get previous batch
-                .join(&(batch_index - 1).to_string())?;
+                .join(&batch.to_string())?;
 
             debug!(?report_url, "fetching reports");
             let rsp = reqwest::get(report_url).await?;
@@ -193,7 +203,12 @@ impl User {
             match rsp.status() {
                 reqwest::StatusCode::NOT_FOUND => {
                     debug!("Got 404 (empty record)");
-                    return Ok(());
+                    continue;
+                }
+                reqwest::StatusCode::SERVICE_UNAVAILABLE => {
+                    debug!("Got 503 (batch not sealed yet)");
+                    self.unsealed.push((shard_id, batch));
+                    continue;
                 }
                 reqwest::StatusCode::OK => {
                     debug!("Got report data from server");
@@ -258,18 +273,17 @@ impl User {
                 .write(Cursor::new(&mut report_bytes))
                 .expect("writing should succeed");
 
-            for shard_id in shard_ids.iter() {
-                let report_url = reqwest::Url::parse(&OPTIONS.server)?
-                    .join(&(shard_id.to_string() + "/"))?
-                    .join("submit/")?;
-
-                debug!(shard_id, "sending report to shard");
-                client
-                    .post(report_url.clone())
-                    .body(report_bytes.clone())
-                    .send()
-                    .await?;
+            if shard_ids.is_empty() {
+                continue;
             }
+            let shards: Vec<_> = shard_ids.iter().map(ToString::to_string).collect();
+            let mut report_url = reqwest::Url::parse(&OPTIONS.server)?.join("submit/")?;
+            report_url
+                .query_pairs_mut()
+                .append_pair("shards", &shards.join(","));
+
+            debug!(?shard_ids, "sending report to shards");
+            client.post(report_url).body(report_bytes).send().await?;
         }
 
         Ok(())